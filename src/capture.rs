@@ -0,0 +1,87 @@
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Schreibt empfangene Frames mit Zeitstempel an eine Mitschnitt-Datei an.
+/// Pro Zeile ein Frame: Zeitstempel (RFC 3339) und die Rohbytes in Hex, so wie
+/// sie von der Eingangsquelle gelesen wurden, z.B.
+/// `2024-05-01T12:00:00.123+02:00 1b1b1b1b01010101...`
+pub struct Recorder {
+    file: File,
+    pending: Vec<u8>,
+}
+
+impl Recorder {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Merkt sich ein Rohbyte des aktuellen Frames.
+    pub fn push(&mut self, byte: u8) {
+        self.pending.push(byte);
+    }
+
+    /// Schreibt die seit dem letzten Frame gesammelten Bytes als eine Zeile.
+    pub fn finish_frame(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let line = format_line(&Local::now().fixed_offset(), &self.pending);
+        self.pending.clear();
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+/// Ein gelesener Frame aus einer Mitschnitt-Datei.
+pub struct Frame {
+    pub time: DateTime<FixedOffset>,
+    pub bytes: Vec<u8>,
+}
+
+fn format_line(time: &DateTime<FixedOffset>, bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{} {}\n",
+        time.to_rfc3339_opts(SecondsFormat::Millis, false),
+        hex
+    )
+}
+
+/// Zerlegt eine Zeile des Mitschnitts. Leere Zeilen und Kommentare (`#`) ergeben `None`.
+pub fn parse_line(line: &str) -> Option<Result<Frame, String>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (time, hex) = match line.split_once(' ') {
+        Some(parts) => parts,
+        None => return Some(Err(format!("missing payload: {}", line))),
+    };
+    let time = match DateTime::parse_from_rfc3339(time) {
+        Ok(t) => t,
+        Err(e) => return Some(Err(format!("invalid timestamp {}: {}", time, e))),
+    };
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Some(Err("invalid hex payload".to_string()));
+    }
+    let bytes: Result<Vec<u8>, _> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect();
+    Some(
+        bytes
+            .map(|bytes| Frame { time, bytes })
+            .map_err(|e| format!("invalid hex payload: {}", e)),
+    )
+}
@@ -0,0 +1,365 @@
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use crate::config::{parse_duration, MeterConfig};
+#[cfg(feature = "rrd")]
+use crate::rrd;
+
+/// Wie lange auf die Eingabeschleife eines Zählers gewartet wird. Sie nimmt
+/// Befehle auch ohne Daten an, hängt aber evtl. beim Öffnen der Quelle.
+const METER_REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Befehl an die Eingabeschleife eines Zählers (dort liegt der Zustand)
+#[derive(Debug, Clone, Copy)]
+pub enum MeterCommand {
+    ResetFeedIn,
+    SetPublishInterval(Duration),
+    SetStatsInterval(Duration),
+}
+
+pub struct MeterRequest {
+    pub command: MeterCommand,
+    /// Antworttext bzw. Fehlermeldung
+    pub reply: oneshot::Sender<Result<String, String>>,
+}
+
+/// Kanal je Zähler, über den `run_input_loop` Befehle empfängt
+pub fn meter_channel() -> (mpsc::Sender<MeterRequest>, mpsc::Receiver<MeterRequest>) {
+    mpsc::channel(8)
+}
+
+/// Befehl auf dem Command-Topic, z.B.
+/// `{"id": 1, "command": "set_publish_interval", "meter": "pv", "value": "30s"}`.
+/// Ohne `meter` gilt der Befehl für alle Zähler.
+#[derive(Deserialize)]
+struct Command {
+    #[serde(default)]
+    id: Value,
+    command: String,
+    meter: Option<String>,
+    value: Option<String>,
+}
+
+/// Antwort auf dem Response-Topic. `ok` nur, wenn der Befehl bei allen
+/// Zählern erfolgreich war, die Einzelergebnisse stehen in `meters`.
+#[derive(Serialize)]
+struct Response<'a> {
+    id: &'a Value,
+    command: &'a str,
+    ok: bool,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    meters: Vec<MeterResult>,
+}
+
+/// Ergebnis eines Befehls für einen Zähler
+#[derive(Serialize)]
+struct MeterResult {
+    meter: String,
+    ok: bool,
+    message: String,
+}
+
+/// Geprüfter Befehl, bevor er für die einzelnen Zähler ausgeführt wird
+enum Action {
+    #[cfg(feature = "rrd")]
+    RegenerateGraphs,
+    #[cfg(feature = "rrd")]
+    BackupRrd,
+    Meter(MeterCommand),
+}
+
+#[derive(Clone)]
+pub struct CommandHandler {
+    meters: Vec<(MeterConfig, mpsc::Sender<MeterRequest>)>,
+    response_topic: String,
+}
+
+impl CommandHandler {
+    pub fn new(
+        meters: Vec<(MeterConfig, mpsc::Sender<MeterRequest>)>,
+        response_topic: &str,
+    ) -> Self {
+        Self {
+            meters,
+            response_topic: response_topic.to_string(),
+        }
+    }
+
+    /// Führt einen empfangenen Befehl aus und veröffentlicht die Antwort
+    pub async fn handle(self, client: AsyncClient, payload: Vec<u8>) {
+        let response = match serde_json::from_slice::<Command>(&payload) {
+            Ok(cmd) => {
+                info!("MQTT command {} (meter {:?})", cmd.command, cmd.meter);
+                let (ok, message, meters) = match self.execute(&cmd).await {
+                    Ok(results) => (
+                        results.iter().all(|r| r.ok),
+                        results
+                            .iter()
+                            .map(|r| format!("{}: {}", r.meter, r.message))
+                            .collect::<Vec<_>>()
+                            .join("; "),
+                        results,
+                    ),
+                    Err(e) => (false, e, Vec::new()),
+                };
+                if !ok {
+                    warn!("MQTT command {} failed: {}", cmd.command, message);
+                }
+                serde_json::to_string(&Response {
+                    id: &cmd.id,
+                    command: &cmd.command,
+                    ok,
+                    message,
+                    meters,
+                })
+            }
+            Err(e) => serde_json::to_string(&Response {
+                id: &Value::Null,
+                command: "",
+                ok: false,
+                message: format!("invalid command: {}", e),
+                meters: Vec::new(),
+            }),
+        };
+        match response {
+            Ok(response) => {
+                let _ = client
+                    .publish(&self.response_topic, QoS::AtLeastOnce, false, response)
+                    .await;
+            }
+            Err(e) => warn!("Error serializing command response: {}", e),
+        }
+    }
+
+    /// Fehler im Befehl selbst brechen ab, Fehler einzelner Zähler nicht: die
+    /// übrigen Zähler haben den Befehl dann schon ausgeführt bzw. führen ihn aus
+    async fn execute(&self, cmd: &Command) -> Result<Vec<MeterResult>, String> {
+        let meters: Vec<_> = match &cmd.meter {
+            Some(name) => self
+                .meters
+                .iter()
+                .filter(|(m, _)| &m.name == name)
+                .collect(),
+            None => self.meters.iter().collect(),
+        };
+        if meters.is_empty() {
+            return Err(format!(
+                "unknown meter {}",
+                cmd.meter.as_deref().unwrap_or("")
+            ));
+        }
+        let duration = || {
+            cmd.value
+                .as_deref()
+                .ok_or_else(|| format!("{} needs a value", cmd.command))
+                .and_then(parse_duration)
+        };
+
+        let action = match cmd.command.as_str() {
+            #[cfg(feature = "rrd")]
+            "regenerate_graphs" => Action::RegenerateGraphs,
+            #[cfg(feature = "rrd")]
+            "backup_rrd" => Action::BackupRrd,
+            #[cfg(not(feature = "rrd"))]
+            "regenerate_graphs" | "backup_rrd" => {
+                return Err("built without RRD support".to_string())
+            }
+            "reset_feed_in" => Action::Meter(MeterCommand::ResetFeedIn),
+            "set_publish_interval" => Action::Meter(MeterCommand::SetPublishInterval(duration()?)),
+            // Die Statistik läuft auf einem Timer, 0 hieße Dauerschleife
+            "set_stats_interval" => match duration()? {
+                interval if interval.is_zero() => {
+                    return Err("stats interval must be greater than 0".to_string())
+                }
+                interval => Action::Meter(MeterCommand::SetStatsInterval(interval)),
+            },
+            other => return Err(format!("unknown command {}", other)),
+        };
+
+        let mut results = Vec::new();
+        for (meter, tx) in meters {
+            let result = match action {
+                #[cfg(feature = "rrd")]
+                Action::RegenerateGraphs => {
+                    let meter = meter.clone();
+                    run_blocking(move || rrd::regenerate_graphs(&meter)).await
+                }
+                #[cfg(feature = "rrd")]
+                Action::BackupRrd => {
+                    let meter = meter.clone();
+                    run_blocking(move || rrd::backup_rrd(&meter)).await
+                }
+                Action::Meter(command) => send(tx, command).await,
+            };
+            let ok = result.is_ok();
+            results.push(MeterResult {
+                meter: meter.name.clone(),
+                ok,
+                message: result.unwrap_or_else(|e| e),
+            });
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(feature = "rrd")]
+async fn run_blocking<F>(f: F) -> Result<String, String>
+where
+    F: FnOnce() -> Result<String, String> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| format!("task failed: {}", e))?
+}
+
+async fn send(tx: &mpsc::Sender<MeterRequest>, command: MeterCommand) -> Result<String, String> {
+    let (reply, rx) = oneshot::channel();
+    tx.try_send(MeterRequest { command, reply })
+        .map_err(|_| "meter is busy".to_string())?;
+    match tokio::time::timeout(METER_REPLY_TIMEOUT, rx).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err("meter input loop has stopped".to_string()),
+        // Die Anfrage verfällt, `run_input_loop` verwirft sie ungelesen
+        Err(_) => Err("meter input loop did not respond".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use clap::Parser;
+    use rumqttc::{EventLoop, MqttOptions, Request};
+
+    /// Eingabeschleife eines Zählers: bestätigt jeden Befehl mit seinem
+    /// Debug-Text oder lehnt ihn ab
+    fn input_loop(mut rx: mpsc::Receiver<MeterRequest>, ok: bool) {
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                let text = format!("{:?}", request.command);
+                let _ = request.reply.send(if ok { Ok(text) } else { Err(text) });
+            }
+        });
+    }
+
+    /// Zähler `haus` und `wp`; `wp` lehnt Befehle ab, wenn `wp_ok` false ist
+    fn handler(wp_ok: bool) -> CommandHandler {
+        let meters = Config::try_parse_from([
+            "sml_rust",
+            "--meter",
+            "haus=/dev/ttyUSB0",
+            "--meter",
+            "wp=/dev/ttyUSB1",
+        ])
+        .unwrap()
+        .meters()
+        .unwrap();
+        let meters = meters
+            .into_iter()
+            .map(|meter| {
+                let (tx, rx) = meter_channel();
+                input_loop(rx, meter.name != "wp" || wp_ok);
+                (meter, tx)
+            })
+            .collect();
+        CommandHandler::new(meters, "sml/response")
+    }
+
+    /// Führt den Befehl aus und liefert die veröffentlichte Antwort
+    async fn respond(handler: &CommandHandler, payload: &str) -> Value {
+        let (client, mut eventloop): (AsyncClient, EventLoop) =
+            AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 4);
+        handler
+            .clone()
+            .handle(client, payload.as_bytes().to_vec())
+            .await;
+        eventloop.clean();
+        match eventloop.pending.pop_front() {
+            Some(Request::Publish(publish)) => {
+                assert_eq!(publish.topic, "sml/response");
+                serde_json::from_slice(&publish.payload).unwrap()
+            }
+            other => panic!("no response published: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_commands_rejected() {
+        let handler = handler(true);
+        for (payload, message) in [
+            ("{\"id\": 1", "invalid command"),
+            (
+                "{\"id\": 1, \"command\": \"reboot\"}",
+                "unknown command reboot",
+            ),
+            (
+                "{\"id\": 1, \"command\": \"reset_feed_in\", \"meter\": \"pv\"}",
+                "unknown meter pv",
+            ),
+            (
+                "{\"id\": 1, \"command\": \"set_publish_interval\"}",
+                "set_publish_interval needs a value",
+            ),
+            (
+                "{\"id\": 1, \"command\": \"set_publish_interval\", \"value\": \"bald\"}",
+                "",
+            ),
+            (
+                "{\"id\": 1, \"command\": \"set_stats_interval\", \"value\": \"0s\"}",
+                "stats interval must be greater than 0",
+            ),
+        ] {
+            let response = respond(&handler, payload).await;
+            assert_eq!(response["ok"], false, "{}", payload);
+            assert!(
+                response["message"].as_str().unwrap().starts_with(message),
+                "{}: {}",
+                payload,
+                response["message"]
+            );
+            assert!(response.get("meters").is_none(), "{}", payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn command_for_one_meter() {
+        let handler = handler(true);
+        let response = respond(
+            &handler,
+            "{\"id\": \"a1\", \"command\": \"set_publish_interval\", \"meter\": \"wp\", \"value\": \"30s\"}",
+        )
+        .await;
+        assert_eq!(response["id"], "a1");
+        assert_eq!(response["command"], "set_publish_interval");
+        assert_eq!(response["ok"], true);
+        assert_eq!(response["message"], "wp: SetPublishInterval(30s)");
+        let meters = response["meters"].as_array().unwrap();
+        assert_eq!(meters.len(), 1);
+        assert_eq!(meters[0]["meter"], "wp");
+        assert_eq!(meters[0]["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn ok_only_if_all_meters_succeed() {
+        let command = "{\"id\": 2, \"command\": \"reset_feed_in\"}";
+        let response = respond(&handler(true), command).await;
+        assert_eq!(response["ok"], true);
+        assert_eq!(response["meters"].as_array().unwrap().len(), 2);
+
+        // Ein Zähler scheitert: Befehl nicht ok, der andere hat ihn trotzdem ausgeführt
+        let response = respond(&handler(false), command).await;
+        assert_eq!(response["id"], 2);
+        assert_eq!(response["ok"], false);
+        assert_eq!(response["message"], "haus: ResetFeedIn; wp: ResetFeedIn");
+        let meters = response["meters"].as_array().unwrap();
+        assert_eq!(meters[0]["meter"], "haus");
+        assert_eq!(meters[0]["ok"], true);
+        assert_eq!(meters[1]["meter"], "wp");
+        assert_eq!(meters[1]["ok"], false);
+    }
+}
@@ -1,12 +1,84 @@
-use clap::Parser;
-use std::path::PathBuf;
+use anyhow::Context;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use serde_json::Value;
+use serialport::{DataBits, FlowControl, Parity, StopBits};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::influx;
+use crate::mqtt::Topics;
+use crate::obis::ObisCode;
+use crate::profile::{self, MeterProfile, Protocol, SerialSettings};
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
+    /// Konfigurationsdatei (TOML oder YAML). Ihre Werte gelten als Standardwerte,
+    /// Umgebungsvariablen und Kommandozeile haben Vorrang.
+    #[arg(long, env = "SML_CONFIG")]
+    pub config: Option<PathBuf>,
+
     #[arg(long, env, default_value = "/dev/ttyUSB0")]
     pub serial_port: String,
 
+    /// Eingangsquelle: `/dev/ttyUSB0`, `tcp://192.168.1.20:8888`,
+    /// `file:///pfad/mitschnitt.bin` oder `stdin`.
+    /// Ohne Angabe wird `--serial-port` verwendet.
+    #[arg(long, env)]
+    pub input: Option<String>,
+
+    /// Zählerprofil mit Schnittstellenparametern und erwarteten OBIS-Kennzahlen,
+    /// z.B. `easymeter-q3a`. Einzelne Parameter können überschrieben werden.
+    #[arg(long, env, value_parser = profile::parse_profile)]
+    pub meter_profile: Option<&'static MeterProfile>,
+
+    /// Protokoll: sml, d0 oder d0-mode-c (Standard: aus dem Zählerprofil, sonst sml)
+    #[arg(long, env, value_parser = profile::parse_protocol)]
+    pub protocol: Option<Protocol>,
+
+    /// Baudrate der seriellen Schnittstelle (Standard: 9600)
+    #[arg(long, env)]
+    pub baud_rate: Option<u32>,
+
+    /// Datenbits: 5, 6, 7 oder 8 (Standard: 8)
+    #[arg(long, env, value_parser = profile::parse_data_bits)]
+    pub data_bits: Option<DataBits>,
+
+    /// Parität: none, even oder odd (Standard: none)
+    #[arg(long, env, value_parser = profile::parse_parity)]
+    pub parity: Option<Parity>,
+
+    /// Stoppbits: 1 oder 2 (Standard: 1)
+    #[arg(long, env, value_parser = profile::parse_stop_bits)]
+    pub stop_bits: Option<StopBits>,
+
+    /// Flusskontrolle: none, software oder hardware (Standard: none)
+    #[arg(long, env, value_parser = profile::parse_flow_control)]
+    pub flow_control: Option<FlowControl>,
+
+    /// Hängt jeden empfangenen Frame mit Zeitstempel an diese Datei an
+    #[arg(long, env)]
+    pub record: Option<PathBuf>,
+
+    /// Spielt einen mit `--record` erstellten Mitschnitt ab (ersetzt `--input`)
+    #[arg(long, env)]
+    pub replay: Option<PathBuf>,
+
+    /// Wiedergabegeschwindigkeit für `--replay`, z.B. `10x`
+    #[arg(long, env, default_value = "1x", value_parser = parse_speed)]
+    pub speed: f64,
+
+    /// Mehrere Zähler in einem Prozess: `NAME=EINGANG[,schlüssel=wert...]`,
+    /// z.B. `waermepumpe=tcp://192.168.1.21:8888,profile=easymeter-q3a`.
+    /// Mehrfach angebbar (in `METERS` durch `;` getrennt); ersetzt dann
+    /// `--input`, `--serial-port` und die Schnittstellenoptionen.
+    #[arg(long = "meter", env = "METERS", value_delimiter = ';', value_parser = parse_meter)]
+    pub meters: Vec<MeterSpec>,
+
+    /// MQTT ganz abschalten (nur RRD und Webserver)
+    #[arg(long, env, default_value_t = true, action = clap::ArgAction::Set)]
+    pub mqtt_enabled: bool,
+
     #[arg(long, env, default_value = "localhost")]
     pub mqtt_broker: String,
 
@@ -14,20 +86,984 @@ pub struct Config {
     pub mqtt_port: u16,
 
     #[arg(long, env)]
-    pub mqtt_user: String,
+    pub mqtt_user: Option<String>,
+
+    #[arg(long, env)]
+    pub mqtt_pass: Option<String>,
+
+    /// Transport zum Broker: tcp, tls, ws oder wss (WebSocket nur mit Feature `websocket`)
+    #[arg(long, env, default_value = "tcp", value_parser = parse_mqtt_transport)]
+    pub mqtt_transport: MqttTransport,
+
+    /// CA-Zertifikat (PEM) für tls/wss. Ohne Angabe gelten die Zertifikate des Systems.
+    #[arg(long, env)]
+    pub mqtt_ca: Option<PathBuf>,
+
+    /// Client-Zertifikat (PEM) für gegenseitige TLS-Authentifizierung, benötigt `--mqtt-ca`
+    #[arg(long, env)]
+    pub mqtt_client_cert: Option<PathBuf>,
 
+    /// Privater Schlüssel (PEM) zum Client-Zertifikat
     #[arg(long, env)]
-    pub mqtt_pass: String,
+    pub mqtt_client_key: Option<PathBuf>,
+
+    /// Pfad des WebSocket-Endpunkts für ws/wss
+    #[arg(long, env, default_value = "/mqtt")]
+    pub mqtt_ws_path: String,
+
+    /// Client-ID beim Broker, muss je Instanz eindeutig sein
+    #[arg(long, env, default_value = "sml1_rust")]
+    pub mqtt_client_id: String,
+
+    /// Status des Dienstes (`online`/`offline`, retained, Last Will)
+    #[arg(long, env, default_value = "sml_rust/status")]
+    pub mqtt_status_topic: String,
+
+    /// Topic für Befehle (JSON, siehe doc/config.md), leer = keine Befehle
+    #[arg(long, env, default_value = "sml_rust/command")]
+    pub mqtt_command_topic: String,
+
+    /// Topic für die Antworten auf Befehle
+    #[arg(long, env, default_value = "sml_rust/response")]
+    pub mqtt_response_topic: String,
+
+    /// Datei für Zählerstände, die bei getrennter Verbindung gepuffert werden
+    #[arg(long, env, default_value = "./bak/mqtt_queue.jsonl")]
+    pub mqtt_queue_path: PathBuf,
+
+    /// Maximale Anzahl gepufferter Nachrichten, 0 = nicht puffern
+    #[arg(long, env, default_value_t = 10000)]
+    pub mqtt_queue_size: usize,
+
+    /// Basis der Zustands-Topics `<base>/sensor/<node>/<object>/state`
+    #[arg(long, env, default_value = "homeassistant")]
+    pub mqtt_base_topic: String,
+
+    /// Discovery-Präfix von Home Assistant
+    #[arg(long, env, default_value = "homeassistant")]
+    pub mqtt_discovery_prefix: String,
+
+    /// Knoten in den Topics für den Zähler ohne `--meter` (sonst der Zählername)
+    #[arg(long, env, default_value = "sml")]
+    pub mqtt_node_id: String,
+
+    /// Wirkleistung (W), unterhalb der die Einspeisung als aktiv gemeldet wird
+    #[arg(long, env, default_value_t = -50.0, allow_negative_numbers = true)]
+    pub feed_in_on_threshold: f64,
+
+    /// Wirkleistung (W), oberhalb der die Einspeisung als beendet gemeldet wird
+    #[arg(long, env, default_value_t = -10.0, allow_negative_numbers = true)]
+    pub feed_in_off_threshold: f64,
+
+    /// Mindestdauer unterhalb der Einschaltschwelle, bevor Einspeisung gemeldet wird
+    #[arg(long, env, default_value = "0s", value_parser = parse_duration)]
+    pub feed_in_on_hold: Duration,
+
+    /// Mindestdauer oberhalb der Ausschaltschwelle, bevor das Ende gemeldet wird
+    #[arg(long, env, default_value = "0s", value_parser = parse_duration)]
+    pub feed_in_off_hold: Duration,
+
+    /// Ohne gültigen Messwert in dieser Zeit gilt ein Zähler als nicht verfügbar
+    #[arg(long, env, default_value = "60s", value_parser = parse_duration)]
+    pub stale_timeout: Duration,
+
+    /// Abstand zwischen zwei Veröffentlichungen der Zählerstände per MQTT
+    #[arg(long, env, default_value = "60s", value_parser = parse_duration)]
+    pub mqtt_publish_interval: Duration,
+
+    /// Wartezeit vor einem erneuten Öffnen der Eingangsquelle
+    #[arg(long, env, default_value = "5s", value_parser = parse_duration)]
+    pub reconnect_delay: Duration,
+
+    /// So lange darf ein serieller Port bzw. eine TCP-Verbindung ohne Daten
+    /// bleiben, bevor die Quelle neu geöffnet wird
+    #[arg(long, env, default_value = "5s", value_parser = parse_duration)]
+    pub read_timeout: Duration,
+
+    /// InfluxDB-Write-Endpunkt, z.B. `http://influx:8086/api/v2/write?org=o&bucket=b`,
+    /// `http://vm:8428/write` oder `udp://influx:8089`; ohne Angabe keine Ausgabe
+    #[arg(long, env)]
+    pub influx_url: Option<String>,
+
+    /// API-Token für InfluxDB 2.x (`Authorization: Token ...`)
+    #[arg(long, env)]
+    pub influx_token: Option<String>,
+
+    #[arg(long, env, default_value = "sml")]
+    pub influx_measurement: String,
+
+    /// Zeilen je Schreibvorgang
+    #[arg(long, env, default_value_t = 100)]
+    pub influx_batch_size: usize,
+
+    /// Spätestens nach dieser Zeit wird geschrieben
+    #[arg(long, env, default_value = "10s", value_parser = parse_duration)]
+    pub influx_flush_interval: Duration,
+
+    /// Maximale Anzahl gepufferter Zeilen, solange InfluxDB nicht erreichbar ist
+    #[arg(long, env, default_value_t = 10000)]
+    pub influx_buffer_size: usize,
+
+    /// SQLite-Datenbank für jeden einzelnen Messwert plus Stunden- und Tageswerte
+    /// (nur mit Feature `sqlite`); ohne Angabe keine Ausgabe
+    #[arg(long, env)]
+    pub sqlite_path: Option<PathBuf>,
+
+    /// RRD-Datenbank und Graphen abschalten, z.B. wenn nur SQLite genutzt wird
+    #[arg(long, env, default_value_t = true, action = clap::ArgAction::Set)]
+    pub rrd_enabled: bool,
+
+    /// Abstand zwischen zwei Aktualisierungen der Stundengraphen
+    #[arg(long, env, default_value = "30s", value_parser = parse_duration)]
+    pub graph_interval: Duration,
 
     #[arg(long, env, default_value = "/tmp/sml_rust/ehz.rrd")]
     pub rrd_path: PathBuf,
 
     #[arg(long, env, default_value = "./bak/ehz.rrd")]
-    pub rrd_backup_path: String,
+    pub rrd_backup_path: PathBuf,
+
+    /// Auflösung einer neu angelegten RRD (bestehende behalten ihre Struktur),
+    /// ganze Sekunden, die eine Stunde glatt teilen
+    #[arg(long, env, default_value = "5s", value_parser = parse_duration)]
+    pub rrd_step: Duration,
+
+    /// Ohne Update in dieser Zeit speichert die RRD unbekannte Werte
+    #[arg(long, env, default_value = "15m", value_parser = parse_duration)]
+    pub rrd_heartbeat: Duration,
+
+    /// Zeilen des Archivs in voller Auflösung (Standard: 24 h bei 5 s)
+    #[arg(long, env, default_value_t = 17280)]
+    pub rrd_rows: u32,
+
+    /// Zeilen des Archivs mit Stundenmitteln (Standard: 2 Jahre)
+    #[arg(long, env, default_value_t = 17520)]
+    pub rrd_hourly_rows: u32,
+
+    /// Datei für integrierte Zählerstände, die einen Neustart überdauern sollen
+    /// (Standard `./bak/sml_state.json`; bei Mitschnitten nur, wenn angegeben)
+    #[arg(long, env)]
+    pub state_path: Option<PathBuf>,
+
+    /// Abstand zwischen zwei Sicherungen der Zählerstände, z.B. `60s` oder `5m`
+    #[arg(long, env, default_value = "60s", value_parser = parse_duration)]
+    pub state_interval: Duration,
 
     #[arg(long, env, default_value = "/tmp/sml_rust")]
-    pub image_output_path: String,
+    pub image_output_path: PathBuf,
 
     #[arg(long, env, default_value = "0.0.0.0:5000")]
     pub server_addr: String,
 }
+
+/// Verbindungsart zum MQTT-Broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MqttTransport {
+    Tcp,
+    Tls,
+    /// MQTT über WebSocket
+    Ws,
+    /// MQTT über WebSocket mit TLS
+    Wss,
+}
+
+/// Quelle des SML-Datenstroms.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    /// Lokales serielles Gerät (IR-Lesekopf)
+    Serial(String),
+    /// TCP-Server im Format `host:port` (ser2net, Tasmota TCP-Bridge)
+    Tcp(String),
+    /// Mitschnitt mit rohen SML-Bytes
+    File(PathBuf),
+    /// Standardeingabe
+    Stdin,
+    /// Wiedergabe eines Mitschnitts mit Zeitstempeln
+    Replay { path: PathBuf, speed: f64 },
+}
+
+impl Input {
+    /// Mitschnitt statt echtem Zähler: ohne ausdrücklichen Pfad wird kein
+    /// Zustand geladen oder gesichert, sonst überschriebe eine Wiedergabe die
+    /// Zählerstände des laufenden Betriebs
+    fn is_capture(&self) -> bool {
+        matches!(self, Input::File(_) | Input::Replay { .. })
+    }
+}
+
+/// Zähler aus `--meter`, so wie auf der Kommandozeile angegeben
+#[derive(Debug, Clone)]
+pub struct MeterSpec {
+    pub name: String,
+    pub input: String,
+    pub profile: Option<&'static MeterProfile>,
+    pub protocol: Option<Protocol>,
+    pub baud_rate: Option<u32>,
+    pub data_bits: Option<DataBits>,
+    pub parity: Option<Parity>,
+    pub stop_bits: Option<StopBits>,
+    pub flow_control: Option<FlowControl>,
+    pub rrd_path: Option<PathBuf>,
+    pub state_path: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub feed_in_on_threshold: Option<f64>,
+    pub feed_in_off_threshold: Option<f64>,
+    pub feed_in_on_hold: Option<Duration>,
+    pub feed_in_off_hold: Option<Duration>,
+    pub stale_timeout: Option<Duration>,
+    pub node_id: Option<String>,
+}
+
+/// Vollständig aufgelöste Einstellungen eines Zählers
+#[derive(Debug, Clone)]
+pub struct MeterConfig {
+    pub name: String,
+    /// MQTT-Topics, Knoten ist `--mqtt-node-id` bzw. der Zählername
+    pub topics: Topics,
+    pub input: Input,
+    pub profile: Option<&'static MeterProfile>,
+    pub protocol: Protocol,
+    pub serial: SerialSettings,
+    pub record: Option<PathBuf>,
+    #[cfg(feature = "rrd")]
+    pub rrd_path: PathBuf,
+    #[cfg(feature = "rrd")]
+    pub rrd_backup_path: PathBuf,
+    /// `None` bei Mitschnitten ohne `--state-path` bzw. `state=`
+    pub state_path: Option<PathBuf>,
+    /// Verzeichnis für die Graphen, unterhalb von `--image-output-path`
+    #[cfg(feature = "rrd")]
+    pub image_dir: PathBuf,
+    /// URL-Pfad der Graphen relativ zu `/images`
+    pub image_url: String,
+    pub feed_in_on_threshold: f64,
+    pub feed_in_off_threshold: f64,
+    pub feed_in_on_hold: Duration,
+    pub feed_in_off_hold: Duration,
+    pub stale_timeout: Duration,
+    pub mqtt_publish_interval: Duration,
+    pub reconnect_delay: Duration,
+    pub read_timeout: Duration,
+}
+
+impl MeterConfig {
+    /// Vom Zählerprofil erwartete OBIS-Kennzahlen
+    pub fn expected_obis(&self) -> &'static [ObisCode] {
+        self.profile.map(|p| p.obis).unwrap_or(&[])
+    }
+}
+
+impl Config {
+    /// Liest Kommandozeile und Umgebung. Werte aus `--config` werden als
+    /// Standardwerte eingesetzt, daher gilt: CLI > Umgebung > Datei > eingebaut.
+    pub fn load() -> anyhow::Result<Self> {
+        let mut command = Config::command();
+        if let Some(path) = config_file_arg() {
+            for (key, values) in read_config_file(&path)? {
+                // `meters` wie die Umgebungsvariable `METERS`
+                let long = match key.as_str() {
+                    "meters" => "meter".to_string(),
+                    _ => key.replace('_', "-"),
+                };
+                let id = command
+                    .get_arguments()
+                    .find(|a| a.get_long() == Some(long.as_str()) && long != "config")
+                    .map(|a| a.get_id().clone())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("unknown option {} in {}", key, path.display())
+                    })?;
+                command = command.mut_arg(id, |arg| arg.default_values(values).required(false));
+            }
+        }
+        let config = Config::from_arg_matches(&command.get_matches())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Werte, die `parse_duration` zulässt, die aber als Takt unbrauchbar sind,
+    /// und die InfluxDB-URL (sonst fiele der Fehler erst beim ersten Schreiben auf)
+    fn validate(&self) -> anyhow::Result<()> {
+        // `tokio::time::interval` bricht bei 0 ab, ein Sleep von 0 dreht leer
+        for (option, interval) in [
+            ("--influx-flush-interval", self.influx_flush_interval),
+            ("--graph-interval", self.graph_interval),
+            ("--reconnect-delay", self.reconnect_delay),
+            ("--read-timeout", self.read_timeout),
+            ("--stale-timeout", self.stale_timeout),
+            ("--state-interval", self.state_interval),
+            ("--rrd-step", self.rrd_step),
+            ("--rrd-heartbeat", self.rrd_heartbeat),
+        ] {
+            if interval.is_zero() {
+                anyhow::bail!("{} must be greater than 0", option);
+            }
+        }
+        // Das Stundenarchiv fasst `3600 / step` Werte zusammen
+        if self.rrd_step.subsec_nanos() != 0 || 3600 % self.rrd_step.as_secs() != 0 {
+            anyhow::bail!("--rrd-step must be whole seconds dividing one hour");
+        }
+        for (option, rows) in [
+            ("--rrd-rows", self.rrd_rows),
+            ("--rrd-hourly-rows", self.rrd_hourly_rows),
+        ] {
+            if rows == 0 {
+                anyhow::bail!("{} must be greater than 0", option);
+            }
+        }
+        if let Some(url) = &self.influx_url {
+            influx::parse_url(url).context("--influx-url")?;
+        }
+        Ok(())
+    }
+}
+
+/// `--config` muss vor dem eigentlichen Parsen bekannt sein
+fn config_file_arg() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("SML_CONFIG").map(PathBuf::from)
+}
+
+/// Liest eine TOML- oder YAML-Datei (nach Endung) als Liste von Option → Werte.
+/// Schlüssel entsprechen den langen Optionsnamen, `-` oder `_` sind gleichwertig.
+fn read_config_file(path: &Path) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading config file {}", path.display()))?;
+    let root: Value = match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&text)
+            .with_context(|| format!("parsing config file {}", path.display()))?,
+        _ => toml::from_str(&text)
+            .with_context(|| format!("parsing config file {}", path.display()))?,
+    };
+    let Value::Object(table) = root else {
+        anyhow::bail!("config file {} must contain a table", path.display());
+    };
+
+    let mut options = Vec::new();
+    for (key, value) in table {
+        let values = match (key.as_str(), value) {
+            (_, Value::Null) => continue,
+            // [[meter]] name = "...", input = "...", ... oder [meter.NAME] input = "..."
+            ("meter" | "meters", Value::Array(list)) => list
+                .into_iter()
+                .map(|m| meter_spec_from_table(None, m))
+                .collect::<anyhow::Result<_>>()?,
+            ("meter" | "meters", Value::Object(map)) => map
+                .into_iter()
+                .map(|(name, m)| meter_spec_from_table(Some(name), m))
+                .collect::<anyhow::Result<_>>()?,
+            (_, Value::Array(list)) => list
+                .into_iter()
+                .map(|v| scalar_to_string(&key, v))
+                .collect::<anyhow::Result<_>>()?,
+            (_, v) => vec![scalar_to_string(&key, v)?],
+        };
+        options.push((key, values));
+    }
+    Ok(options)
+}
+
+fn scalar_to_string(key: &str, value: Value) -> anyhow::Result<String> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => anyhow::bail!("option {} must be a string, number or boolean", key),
+    }
+}
+
+/// Zählertabelle aus der Datei → `NAME=EINGANG,schlüssel=wert,...` wie bei `--meter`
+fn meter_spec_from_table(name: Option<String>, value: Value) -> anyhow::Result<String> {
+    let Value::Object(mut table) = value else {
+        // Kurzform: meter = ["haus=/dev/ttyUSB0,profile=ehz-edl21"]
+        return scalar_to_string("meter", value);
+    };
+    let name = match name {
+        Some(n) => n,
+        None => scalar_to_string("meter.name", table.remove("name").unwrap_or(Value::Null))?,
+    };
+    let input = scalar_to_string("meter.input", table.remove("input").unwrap_or(Value::Null))
+        .with_context(|| format!("meter {} needs an input", name))?;
+    let mut spec = format!("{}={}", name, input);
+    for (key, v) in table {
+        let v = scalar_to_string(&format!("meter.{}", key), v)?;
+        spec.push_str(&format!(",{}={}", key.replace('_', "-"), v));
+    }
+    Ok(spec)
+}
+
+/// Name des Zählers, wenn kein `--meter` angegeben ist (bisherige Topics `.../sml/...`)
+pub const DEFAULT_METER: &str = "sml";
+
+/// Zustandsdatei ohne `--state-path`
+const DEFAULT_STATE_PATH: &str = "./bak/sml_state.json";
+
+impl Config {
+    /// Alle zu lesenden Zähler. Ohne `--meter` ist es genau einer aus den
+    /// Einzeloptionen, mit den bisherigen Pfaden.
+    pub fn meters(&self) -> anyhow::Result<Vec<MeterConfig>> {
+        let meters = self.resolve_meters()?;
+        for m in &meters {
+            // Ohne Hysterese würde der Status bei jedem Wert dazwischen umschalten
+            if m.feed_in_on_threshold > m.feed_in_off_threshold {
+                anyhow::bail!(
+                    "meter {}: feed-in on threshold {} W must not be above off threshold {} W",
+                    m.name,
+                    m.feed_in_on_threshold,
+                    m.feed_in_off_threshold
+                );
+            }
+        }
+        Ok(meters)
+    }
+
+    fn resolve_meters(&self) -> anyhow::Result<Vec<MeterConfig>> {
+        if self.meters.is_empty() {
+            return Ok(vec![MeterConfig {
+                name: DEFAULT_METER.to_string(),
+                topics: self.topics(&self.mqtt_node_id),
+                input: self.input(),
+                profile: self.meter_profile,
+                protocol: self.protocol(),
+                serial: self.serial_settings(),
+                record: self.record.clone(),
+                #[cfg(feature = "rrd")]
+                rrd_path: self.rrd_path.clone(),
+                #[cfg(feature = "rrd")]
+                rrd_backup_path: self.rrd_backup_path.clone(),
+                state_path: self.state_path.clone().or_else(|| {
+                    (!self.input().is_capture()).then(|| PathBuf::from(DEFAULT_STATE_PATH))
+                }),
+                #[cfg(feature = "rrd")]
+                image_dir: self.image_output_path.clone(),
+                image_url: String::new(),
+                feed_in_on_threshold: self.feed_in_on_threshold,
+                feed_in_off_threshold: self.feed_in_off_threshold,
+                feed_in_on_hold: self.feed_in_on_hold,
+                feed_in_off_hold: self.feed_in_off_hold,
+                stale_timeout: self.stale_timeout,
+                mqtt_publish_interval: self.mqtt_publish_interval,
+                reconnect_delay: self.reconnect_delay,
+                read_timeout: self.read_timeout,
+            }]);
+        }
+
+        let mut meters: Vec<MeterConfig> = Vec::new();
+        for spec in &self.meters {
+            if meters.iter().any(|m| m.name == spec.name) {
+                anyhow::bail!("meter name {} used more than once", spec.name);
+            }
+            let input = parse_input(&spec.input);
+            let protocol = spec
+                .protocol
+                .or(spec.profile.map(|p| p.protocol))
+                .unwrap_or(Protocol::Sml);
+            let base = spec
+                .profile
+                .map(|p| p.serial)
+                .unwrap_or_else(|| protocol.default_serial());
+            meters.push(MeterConfig {
+                name: spec.name.clone(),
+                topics: self.topics(spec.node_id.as_deref().unwrap_or(&spec.name)),
+                input: input.clone(),
+                profile: spec.profile,
+                protocol,
+                serial: SerialSettings {
+                    baud_rate: spec.baud_rate.unwrap_or(base.baud_rate),
+                    data_bits: spec.data_bits.unwrap_or(base.data_bits),
+                    parity: spec.parity.unwrap_or(base.parity),
+                    stop_bits: spec.stop_bits.unwrap_or(base.stop_bits),
+                    flow_control: spec.flow_control.unwrap_or(base.flow_control),
+                },
+                record: spec.record.clone(),
+                // Standardpfade je Zähler neben den globalen Dateien
+                #[cfg(feature = "rrd")]
+                rrd_path: spec
+                    .rrd_path
+                    .clone()
+                    .unwrap_or_else(|| self.rrd_path.with_file_name(format!("{}.rrd", spec.name))),
+                #[cfg(feature = "rrd")]
+                rrd_backup_path: self
+                    .rrd_backup_path
+                    .with_file_name(format!("{}.rrd", spec.name)),
+                state_path: spec.state_path.clone().or_else(|| {
+                    let global = match &self.state_path {
+                        Some(path) => path.clone(),
+                        None if input.is_capture() => return None,
+                        None => PathBuf::from(DEFAULT_STATE_PATH),
+                    };
+                    Some(global.with_file_name(format!("{}_state.json", spec.name)))
+                }),
+                #[cfg(feature = "rrd")]
+                image_dir: self.image_output_path.join(&spec.name),
+                image_url: format!("{}/", spec.name),
+                feed_in_on_threshold: spec
+                    .feed_in_on_threshold
+                    .unwrap_or(self.feed_in_on_threshold),
+                feed_in_off_threshold: spec
+                    .feed_in_off_threshold
+                    .unwrap_or(self.feed_in_off_threshold),
+                feed_in_on_hold: spec.feed_in_on_hold.unwrap_or(self.feed_in_on_hold),
+                feed_in_off_hold: spec.feed_in_off_hold.unwrap_or(self.feed_in_off_hold),
+                stale_timeout: spec.stale_timeout.unwrap_or(self.stale_timeout),
+                mqtt_publish_interval: self.mqtt_publish_interval,
+                reconnect_delay: self.reconnect_delay,
+                read_timeout: self.read_timeout,
+            });
+        }
+        Ok(meters)
+    }
+
+    fn topics(&self, node: &str) -> Topics {
+        Topics::new(
+            &self.mqtt_base_topic,
+            &self.mqtt_discovery_prefix,
+            node,
+            &self.mqtt_status_topic,
+        )
+    }
+}
+
+impl Config {
+    pub fn input(&self) -> Input {
+        if let Some(path) = &self.replay {
+            return Input::Replay {
+                path: path.clone(),
+                speed: self.speed,
+            };
+        }
+        match self.input.as_deref() {
+            Some(s) => parse_input(s),
+            None => Input::Serial(self.serial_port.clone()),
+        }
+    }
+}
+
+impl Config {
+    /// Schnittstellenparameter aus dem Zählerprofil, überschrieben durch einzelne Optionen
+    pub fn serial_settings(&self) -> SerialSettings {
+        let base = self
+            .meter_profile
+            .map(|p| p.serial)
+            .unwrap_or_else(|| self.protocol().default_serial());
+        SerialSettings {
+            baud_rate: self.baud_rate.unwrap_or(base.baud_rate),
+            data_bits: self.data_bits.unwrap_or(base.data_bits),
+            parity: self.parity.unwrap_or(base.parity),
+            stop_bits: self.stop_bits.unwrap_or(base.stop_bits),
+            flow_control: self.flow_control.unwrap_or(base.flow_control),
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+            .or(self.meter_profile.map(|p| p.protocol))
+            .unwrap_or(Protocol::Sml)
+    }
+}
+
+fn parse_input(s: &str) -> Input {
+    if s == "stdin" || s == "-" {
+        Input::Stdin
+    } else if let Some(addr) = s.strip_prefix("tcp://") {
+        Input::Tcp(addr.to_string())
+    } else if let Some(path) = s.strip_prefix("file://") {
+        Input::File(PathBuf::from(path))
+    } else {
+        Input::Serial(s.strip_prefix("serial://").unwrap_or(s).to_string())
+    }
+}
+
+/// `name=eingang[,profile=..][,protocol=..][,baud=..][,data-bits=..][,parity=..]
+/// [,stop-bits=..][,flow-control=..][,rrd=..][,state=..][,record=..]
+/// [,feed-in-on-threshold=..][,feed-in-off-threshold=..][,feed-in-on-hold=..]
+/// [,feed-in-off-hold=..][,stale-timeout=..]`
+fn parse_meter(s: &str) -> Result<MeterSpec, String> {
+    let (name, rest) = s
+        .split_once('=')
+        .ok_or_else(|| format!("meter must be NAME=INPUT[,key=value...]: {}", s))?;
+    let name = name.trim();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(format!(
+            "meter name may only contain letters, digits, _ and -: {}",
+            name
+        ));
+    }
+    let mut parts = rest.split(',');
+    let input = parts.next().unwrap_or_default().trim();
+    if input.is_empty() {
+        return Err(format!("meter {} has no input", name));
+    }
+    let mut spec = MeterSpec {
+        name: name.to_string(),
+        input: input.to_string(),
+        profile: None,
+        protocol: None,
+        baud_rate: None,
+        data_bits: None,
+        parity: None,
+        stop_bits: None,
+        flow_control: None,
+        rrd_path: None,
+        state_path: None,
+        record: None,
+        feed_in_on_threshold: None,
+        feed_in_off_threshold: None,
+        feed_in_on_hold: None,
+        feed_in_off_hold: None,
+        stale_timeout: None,
+        node_id: None,
+    };
+    for part in parts {
+        let (key, value) = part
+            .split_once('=')
+            .ok_or_else(|| format!("meter option must be key=value: {}", part))?;
+        let value = value.trim();
+        match key.trim() {
+            "profile" => spec.profile = Some(profile::parse_profile(value)?),
+            "protocol" => spec.protocol = Some(profile::parse_protocol(value)?),
+            "baud" | "baud-rate" => {
+                spec.baud_rate = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid baud rate: {}", value))?,
+                )
+            }
+            "data-bits" => spec.data_bits = Some(profile::parse_data_bits(value)?),
+            "parity" => spec.parity = Some(profile::parse_parity(value)?),
+            "stop-bits" => spec.stop_bits = Some(profile::parse_stop_bits(value)?),
+            "flow-control" => spec.flow_control = Some(profile::parse_flow_control(value)?),
+            // `rrd_path`/`state_path` aus Zählertabellen der Konfigurationsdatei
+            "rrd" | "rrd-path" => spec.rrd_path = Some(PathBuf::from(value)),
+            "state" | "state-path" => spec.state_path = Some(PathBuf::from(value)),
+            "record" => spec.record = Some(PathBuf::from(value)),
+            "feed-in-on-threshold" => {
+                spec.feed_in_on_threshold = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid threshold: {}", value))?,
+                )
+            }
+            "feed-in-off-threshold" => {
+                spec.feed_in_off_threshold = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid threshold: {}", value))?,
+                )
+            }
+            "feed-in-on-hold" => spec.feed_in_on_hold = Some(parse_duration(value)?),
+            "feed-in-off-hold" => spec.feed_in_off_hold = Some(parse_duration(value)?),
+            "stale-timeout" => match parse_duration(value)? {
+                timeout if timeout.is_zero() => {
+                    return Err("stale-timeout must be greater than 0".to_string())
+                }
+                timeout => spec.stale_timeout = Some(timeout),
+            },
+            // `node_id` aus Zählertabellen der Konfigurationsdatei
+            "node" | "node-id" => {
+                if value.is_empty() || value.contains(['/', '+', '#']) {
+                    return Err(format!("invalid MQTT node id: {}", value));
+                }
+                spec.node_id = Some(value.to_string())
+            }
+            other => return Err(format!("unknown meter option {}", other)),
+        }
+    }
+    Ok(spec)
+}
+
+fn parse_mqtt_transport(s: &str) -> Result<MqttTransport, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "tcp" | "mqtt" => Ok(MqttTransport::Tcp),
+        "tls" | "mqtts" | "ssl" => Ok(MqttTransport::Tls),
+        "ws" => Ok(MqttTransport::Ws),
+        "wss" => Ok(MqttTransport::Wss),
+        _ => Err(format!("MQTT transport must be tcp, tls, ws or wss: {}", s)),
+    }
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    let speed: f64 = s
+        .trim_end_matches(['x', 'X'])
+        .parse()
+        .map_err(|_| format!("invalid speed: {}", s))?;
+    if speed.is_finite() && speed > 0.0 {
+        Ok(speed)
+    } else {
+        Err(format!("speed must be greater than 0: {}", s))
+    }
+}
+
+/// Dauer mit Einheit `ms`, `s`, `m` oder `h`; ohne Einheit in Sekunden
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let n: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(n)),
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "m" | "min" => Ok(Duration::from_secs(n * 60)),
+        "h" => Ok(Duration::from_secs(n * 3600)),
+        _ => Err(format!(
+            "invalid duration unit in {} (use ms, s, m or h)",
+            s
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(args: &[&str]) -> Config {
+        let base = ["sml_rust", "--mqtt-user", "sml", "--mqtt-pass", "sml"];
+        Config::try_parse_from(base.iter().chain(args)).unwrap()
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5 min"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("1.5s").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("3d").is_err());
+    }
+
+    #[test]
+    fn parse_speed_values() {
+        assert_eq!(parse_speed("1"), Ok(1.0));
+        assert_eq!(parse_speed("10x"), Ok(10.0));
+        assert_eq!(parse_speed("0.5X"), Ok(0.5));
+        assert!(parse_speed("0").is_err());
+        assert!(parse_speed("-2").is_err());
+        assert!(parse_speed("inf").is_err());
+        assert!(parse_speed("fast").is_err());
+    }
+
+    #[test]
+    fn parse_meter_options() {
+        let spec = parse_meter(
+            "waermepumpe=tcp://192.168.1.21:8888,profile=easymeter-q3d,baud=19200,\
+             state=/var/lib/sml/wp.json,feed-in-on-hold=30s",
+        )
+        .unwrap();
+        assert_eq!(spec.name, "waermepumpe");
+        assert_eq!(spec.input, "tcp://192.168.1.21:8888");
+        assert_eq!(spec.profile.map(|p| p.name), Some("easymeter-q3d"));
+        assert_eq!(spec.baud_rate, Some(19200));
+        assert_eq!(spec.state_path, Some(PathBuf::from("/var/lib/sml/wp.json")));
+        assert_eq!(spec.feed_in_on_hold, Some(Duration::from_secs(30)));
+
+        let spec = parse_meter("haus=/dev/ttyUSB0,rrd=/tmp/haus.rrd,protocol=d0").unwrap();
+        assert_eq!(spec.rrd_path, Some(PathBuf::from("/tmp/haus.rrd")));
+        assert_eq!(spec.protocol, Some(Protocol::D0 { mode_c: false }));
+
+        // `rrd_path`/`state_path` aus Zählertabellen der Konfigurationsdatei
+        let spec =
+            parse_meter("haus=/dev/ttyUSB0,rrd-path=/tmp/h.rrd,state-path=/tmp/h.json").unwrap();
+        assert_eq!(spec.rrd_path, Some(PathBuf::from("/tmp/h.rrd")));
+        assert_eq!(spec.state_path, Some(PathBuf::from("/tmp/h.json")));
+    }
+
+    #[test]
+    fn parse_meter_errors() {
+        assert!(parse_meter("/dev/ttyUSB0").is_err());
+        assert!(parse_meter("haus/keller=/dev/ttyUSB0").is_err());
+        assert!(parse_meter("haus=").is_err());
+        assert!(parse_meter("haus=/dev/ttyUSB0,baud").is_err());
+        assert!(parse_meter("haus=/dev/ttyUSB0,baud=schnell").is_err());
+        assert!(parse_meter("haus=/dev/ttyUSB0,color=red").is_err());
+        assert!(parse_meter("haus=/dev/ttyUSB0,profile=unbekannt").is_err());
+        assert!(parse_meter("haus=/dev/ttyUSB0,stale-timeout=0s").is_err());
+    }
+
+    #[test]
+    fn meter_paths_next_to_global_files() {
+        let config = config(&[
+            "--meter",
+            "haus=/dev/ttyUSB0",
+            "--meter",
+            "wp=tcp://192.168.1.21:8888",
+        ]);
+        let meters = config.meters().unwrap();
+        assert_eq!(meters[0].input, Input::Serial("/dev/ttyUSB0".to_string()));
+        assert_eq!(
+            meters[0].state_path,
+            Some(PathBuf::from(DEFAULT_STATE_PATH).with_file_name("haus_state.json"))
+        );
+        assert_eq!(meters[1].input, Input::Tcp("192.168.1.21:8888".to_string()));
+    }
+
+    #[test]
+    fn capture_inputs_without_state_path() {
+        let meters = config(&[
+            "--meter",
+            "a=file:///tmp/a.bin",
+            "--meter",
+            "b=/dev/ttyUSB0",
+            "--meter",
+            "c=file:///tmp/c.bin,state=/tmp/c.json",
+        ])
+        .meters()
+        .unwrap();
+        assert_eq!(meters[0].state_path, None);
+        assert!(meters[1].state_path.is_some());
+        assert_eq!(meters[2].state_path, Some(PathBuf::from("/tmp/c.json")));
+
+        let meters = config(&["--replay", "/tmp/a.cap"]).meters().unwrap();
+        assert_eq!(meters[0].state_path, None);
+        let meters = config(&["--replay", "/tmp/a.cap", "--state-path", "/tmp/s.json"])
+            .meters()
+            .unwrap();
+        assert_eq!(meters[0].state_path, Some(PathBuf::from("/tmp/s.json")));
+    }
+
+    #[cfg(feature = "rrd")]
+    #[test]
+    fn meter_rrd_paths_next_to_global_files() {
+        let config = config(&[
+            "--meter",
+            "haus=/dev/ttyUSB0",
+            "--meter",
+            "wp=tcp://192.168.1.21:8888,rrd=/tmp/wp.rrd",
+        ]);
+        let meters = config.meters().unwrap();
+        assert_eq!(
+            meters[0].rrd_path,
+            config.rrd_path.with_file_name("haus.rrd")
+        );
+        assert_eq!(meters[1].rrd_path, PathBuf::from("/tmp/wp.rrd"));
+        assert_eq!(meters[1].image_dir, config.image_output_path.join("wp"));
+    }
+
+    #[test]
+    fn meter_serial_from_profile() {
+        let meters = config(&[
+            "--meter",
+            "q3d=/dev/ttyUSB2,profile=easymeter-q3d,parity=none",
+        ])
+        .meters()
+        .unwrap();
+        let q3d = profile::parse_profile("easymeter-q3d").unwrap();
+        assert_eq!(meters[0].protocol, q3d.protocol);
+        assert_eq!(meters[0].serial.baud_rate, q3d.serial.baud_rate);
+        assert_eq!(meters[0].serial.parity, Parity::None);
+    }
+
+    #[test]
+    fn meter_serial_defaults_from_protocol() {
+        let meters = config(&[
+            "--meter",
+            "sml=/dev/ttyUSB0",
+            "--meter",
+            "d0=/dev/ttyUSB1,protocol=d0",
+            "--meter",
+            "mode-c=/dev/ttyUSB2,protocol=d0-mode-c,baud=9600",
+        ])
+        .meters()
+        .unwrap();
+        assert_eq!(meters[0].serial, Protocol::Sml.default_serial());
+        assert_eq!(
+            meters[1].serial,
+            Protocol::D0 { mode_c: false }.default_serial()
+        );
+        assert_eq!(meters[2].serial.baud_rate, 9600);
+        assert_eq!(meters[2].serial.parity, Parity::Even);
+
+        // Ohne `--meter` ebenso
+        let config = config(&["--protocol", "d0"]);
+        assert_eq!(
+            config.serial_settings(),
+            Protocol::D0 { mode_c: false }.default_serial()
+        );
+    }
+
+    #[test]
+    fn meters_reject_duplicates_and_missing_hysteresis() {
+        assert!(
+            config(&["--meter", "a=/dev/ttyUSB0", "--meter", "a=/dev/ttyUSB1"])
+                .meters()
+                .is_err()
+        );
+        assert!(config(&[
+            "--feed-in-on-threshold",
+            "-5",
+            "--feed-in-off-threshold",
+            "-10"
+        ])
+        .meters()
+        .is_err());
+    }
+
+    #[test]
+    fn meters_from_config_file() {
+        let path = std::env::temp_dir().join(format!("sml_rust_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "mqtt_node_id = \"zuhause\"\n\
+             [meter.haus]\n\
+             input = \"/dev/ttyUSB0\"\n\
+             node_id = \"keller\"\n\
+             state_path = \"/tmp/haus.json\"\n",
+        )
+        .unwrap();
+        let options = read_config_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        let options = options.unwrap();
+
+        assert!(options.contains(&("mqtt_node_id".to_string(), vec!["zuhause".to_string()])));
+        let (_, meters) = options.iter().find(|(key, _)| key == "meter").unwrap();
+        assert_eq!(meters.len(), 1);
+        let spec = parse_meter(&meters[0]).unwrap();
+        assert_eq!(spec.name, "haus");
+        assert_eq!(spec.input, "/dev/ttyUSB0");
+        assert_eq!(spec.node_id.as_deref(), Some("keller"));
+        assert_eq!(spec.state_path, Some(PathBuf::from("/tmp/haus.json")));
+    }
+
+    #[test]
+    fn validate_rejects_unusable_values() {
+        assert!(config(&[]).validate().is_ok());
+        assert!(config(&["--influx-url", "udp://influx:8089"])
+            .validate()
+            .is_ok());
+        assert!(config(&["--influx-url", "influx:8086/write"])
+            .validate()
+            .is_err());
+        for option in [
+            "--graph-interval",
+            "--influx-flush-interval",
+            "--reconnect-delay",
+            "--read-timeout",
+            "--stale-timeout",
+            "--state-interval",
+            "--rrd-step",
+            "--rrd-heartbeat",
+        ] {
+            assert!(config(&[option, "0s"]).validate().is_err(), "{}", option);
+        }
+        assert!(config(&["--rrd-step", "10s"]).validate().is_ok());
+        assert!(config(&["--rrd-step", "7s"]).validate().is_err());
+        assert!(config(&["--rrd-step", "2500ms"]).validate().is_err());
+        assert!(config(&["--rrd-rows", "0"]).validate().is_err());
+    }
+}
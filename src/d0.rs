@@ -0,0 +1,461 @@
+use serialport::SerialPort;
+use std::io;
+use std::time::Duration;
+
+use crate::obis::{self, ObisCode, Register, RegisterValue, Registers};
+
+/// Maximale Länge eines D0-Telegramms, danach wird verworfen
+const MAX_TELEGRAM_LEN: usize = 8192;
+
+/// DLMS-Einheitencodes (wie im SML-Feld `unit`)
+const UNIT_W: u8 = 27;
+const UNIT_VA: u8 = 28;
+const UNIT_VAR: u8 = 29;
+const UNIT_WH: u8 = 30;
+const UNIT_VAH: u8 = 31;
+const UNIT_VARH: u8 = 32;
+const UNIT_A: u8 = 33;
+const UNIT_V: u8 = 35;
+const UNIT_HZ: u8 = 44;
+
+#[derive(Debug)]
+pub enum D0Error {
+    TooLong,
+    Incomplete,
+}
+
+/// Sammelt ASCII-Bytes eines IEC 62056-21 Telegramms (`/...` bis `!`).
+pub struct Decoder {
+    buf: Vec<u8>,
+    in_telegram: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            in_telegram: false,
+        }
+    }
+
+    /// Liefert das vollständige Telegramm, sobald die Endezeile `!` empfangen wurde.
+    pub fn push_byte(&mut self, byte: u8) -> Result<Option<Vec<u8>>, D0Error> {
+        // 7E1-Daten über eine 8N1-Leitung: Paritätsbit ausblenden
+        let byte = byte & 0x7f;
+
+        if byte == b'/' {
+            let incomplete = self.in_telegram && !self.buf.is_empty();
+            self.buf.clear();
+            self.buf.push(byte);
+            self.in_telegram = true;
+            return if incomplete {
+                Err(D0Error::Incomplete)
+            } else {
+                Ok(None)
+            };
+        }
+        if !self.in_telegram {
+            return Ok(None);
+        }
+        if self.buf.len() >= MAX_TELEGRAM_LEN {
+            self.buf.clear();
+            self.in_telegram = false;
+            return Err(D0Error::TooLong);
+        }
+        self.buf.push(byte);
+
+        if byte == b'\n' && self.last_line().starts_with(b"!") {
+            self.in_telegram = false;
+            return Ok(Some(std::mem::take(&mut self.buf)));
+        }
+        Ok(None)
+    }
+
+    fn last_line(&self) -> &[u8] {
+        let body = &self.buf[..self.buf.len() - 1];
+        let start = body.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        let line = &body[start..];
+        // STX vor den Daten im Modus C ignorieren
+        line.strip_prefix(&[0x02]).unwrap_or(line)
+    }
+}
+
+/// Zerlegt ein D0-Telegramm in Register. Die Identifikationszeile wird
+/// als Herstellerkennung (`129-129:199.130.3`) abgelegt.
+pub fn parse(telegram: &[u8]) -> Registers {
+    let text = String::from_utf8_lossy(telegram);
+    let mut registers = Registers::new();
+
+    for line in text.lines() {
+        let line = line.trim_matches(|c: char| c.is_whitespace() || c == '\x02' || c == '\x03');
+        if let Some(ident) = line.strip_prefix('/') {
+            registers.insert(
+                obis::HERSTELLER,
+                Register {
+                    value: RegisterValue::Bytes(ident.as_bytes().to_vec()),
+                    scaler: None,
+                    unit: None,
+                    status: None,
+                },
+            );
+            continue;
+        }
+        if let Some((code, register)) = parse_line(line) {
+            registers.insert(code, register);
+        }
+    }
+    registers
+}
+
+/// `1-0:1.8.0*255(001234.5678*kWh)` → Register
+fn parse_line(line: &str) -> Option<(ObisCode, Register)> {
+    let open = line.find('(')?;
+    let obis = parse_obis(&line[..open])?;
+    let rest = &line[open + 1..];
+    let content = &rest[..rest.find(')')?];
+
+    let (number, unit) = match content.split_once('*') {
+        Some((n, u)) => (n, Some(u)),
+        None => (content, None),
+    };
+
+    match parse_number(number) {
+        Some((mantissa, decimals)) => {
+            let (unit, exp) = match unit {
+                Some(u) => parse_unit(u)?,
+                None => (None, 0),
+            };
+            // 001234.5678*kWh → 12345678 mit Skalierer -4 + 3 in Wh
+            let scaler = i8::try_from(exp - decimals).ok()?;
+            Some((
+                obis,
+                Register {
+                    value: RegisterValue::Int(mantissa),
+                    scaler: Some(scaler),
+                    unit,
+                    status: None,
+                },
+            ))
+        }
+        None => Some((
+            obis,
+            Register {
+                value: RegisterValue::Bytes(content.as_bytes().to_vec()),
+                scaler: None,
+                unit: None,
+                status: None,
+            },
+        )),
+    }
+}
+
+/// Akzeptiert `A-B:C.D.E*F`, `A-B:C.D.E` und die Kurzform `C.D.E`
+fn parse_obis(s: &str) -> Option<ObisCode> {
+    let s = s.trim();
+    let (ab, cdef) = match s.split_once(':') {
+        Some((ab, rest)) => (Some(ab), rest),
+        None => (None, s),
+    };
+    let (a, b) = match ab {
+        Some(ab) => {
+            let (a, b) = ab.split_once('-')?;
+            (a.parse().ok()?, b.parse().ok()?)
+        }
+        None => (1, 0),
+    };
+    let (cde, f) = match cdef.split_once(['*', '&']) {
+        Some((cde, f)) => (cde, f.parse().ok()?),
+        None => (cdef, 255),
+    };
+    let mut parts = cde.split('.');
+    let c = parts.next()?.parse().ok()?;
+    let d = parts.next()?.parse().ok()?;
+    let e = parts.next().map_or(Some(0), |e| e.parse().ok())?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(ObisCode::new(a, b, c, d, e, f))
+}
+
+/// Dezimalzahl → (Mantisse, Anzahl Nachkommastellen)
+fn parse_number(s: &str) -> Option<(i64, i32)> {
+    let s = s.trim();
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let (int_part, frac_part) = digits.split_once('.').unwrap_or((digits, ""));
+    if int_part.is_empty() && frac_part.is_empty() {
+        return None;
+    }
+    if !int_part
+        .bytes()
+        .chain(frac_part.bytes())
+        .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let mantissa: i64 = format!("{}{}", int_part, frac_part).parse().ok()?;
+    let mantissa = if negative { -mantissa } else { mantissa };
+    Some((mantissa, frac_part.len() as i32))
+}
+
+/// Einheit → (DLMS-Code, Zehnerpotenz des Präfixes)
+fn parse_unit(s: &str) -> Option<(Option<u8>, i32)> {
+    let s = s.trim();
+    let (base, exp) = match s.strip_prefix('k') {
+        Some(base) if !base.is_empty() => (base, 3),
+        _ => match s.strip_prefix('M') {
+            Some(base) if !base.is_empty() => (base, 6),
+            _ => (s, 0),
+        },
+    };
+    let code = match base {
+        "W" => UNIT_W,
+        "VA" => UNIT_VA,
+        "var" => UNIT_VAR,
+        "Wh" => UNIT_WH,
+        "VAh" => UNIT_VAH,
+        "varh" => UNIT_VARH,
+        "A" => UNIT_A,
+        "V" => UNIT_V,
+        "Hz" => UNIT_HZ,
+        _ => return Some((None, 0)),
+    };
+    Some((Some(code), exp))
+}
+
+// --- Modus C: Anfrage mit 300 Baud, anschließend Umschaltung der Baudrate ---
+
+/// Pause zwischen zwei Abfragen im Modus C
+pub const MODE_C_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Führt eine Abfrage im Modus C durch und liefert das vollständige Telegramm.
+/// Der Port muss mit der Start-Baudrate (üblicherweise 300 7E1) geöffnet sein.
+pub fn mode_c_readout(port: &mut dyn SerialPort, initial_baud: u32) -> io::Result<Vec<u8>> {
+    port.set_baud_rate(initial_baud)?;
+    port.clear(serialport::ClearBuffer::All)?;
+    port.write_all(b"/?!\r\n")?;
+    port.flush()?;
+
+    let ident = read_line(port)?;
+    // Identifikation: /XXXZ..., Z gibt die maximale Baudrate an
+    let baud_char = ident.get(4).copied().unwrap_or(b'0');
+    let target_baud = match baud_char {
+        b'0' => 300,
+        b'1' => 600,
+        b'2' => 1200,
+        b'3' => 2400,
+        b'4' => 4800,
+        b'5' => 9600,
+        b'6' => 19200,
+        _ => initial_baud,
+    };
+    let ack_char = if target_baud == initial_baud {
+        b'0'
+    } else {
+        baud_char
+    };
+
+    // ACK 0 Z 0: Datenauslesung mit der angebotenen Baudrate
+    port.write_all(&[0x06, b'0', ack_char, b'0', b'\r', b'\n'])?;
+    port.flush()?;
+    // Bestätigung vollständig mit der alten Baudrate senden lassen
+    std::thread::sleep(Duration::from_millis(300));
+    if target_baud != initial_baud {
+        port.set_baud_rate(target_baud)?;
+    }
+
+    let mut telegram = ident;
+    let mut decoder = Decoder::new();
+    for &b in &telegram {
+        let _ = decoder.push_byte(b);
+    }
+    let mut buf = [0u8; 64];
+    loop {
+        let n = port.read(&mut buf)?;
+        for &b in &buf[..n] {
+            telegram.push(b);
+            match decoder.push_byte(b) {
+                Ok(Some(_)) => return Ok(telegram),
+                Ok(None) => {}
+                Err(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{:?}", e),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+fn read_line(port: &mut dyn SerialPort) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        port.read_exact(&mut byte)?;
+        let b = byte[0] & 0x7f;
+        if line.is_empty() && b != b'/' {
+            continue;
+        }
+        line.push(b);
+        if b == b'\n' {
+            return Ok(line);
+        }
+        if line.len() > 128 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "identification line too long",
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Mitschnitt eines EasyMeter Q3D (Modus D, 9600 7E1)
+    pub(crate) const EASYMETER_Q3D: &[u8] = b"/ESY5Q3DA1004 V3.04\r\n\
+        \r\n\
+        1-0:0.0.0*255(1ESY1160408383)\r\n\
+        1-0:1.8.0*255(00000527.0326214*kWh)\r\n\
+        1-0:21.7.255*255(000100.84*W)\r\n\
+        1-0:41.7.255*255(000020.57*W)\r\n\
+        1-0:61.7.255*255(000014.48*W)\r\n\
+        1-0:1.7.255*255(000135.89*W)\r\n\
+        1-0:96.5.5*255(82)\r\n\
+        0-0:96.1.255*255(1ESY1160408383)\r\n\
+        !\r\n";
+
+    fn decode_all(bytes: &[u8]) -> Vec<Result<Option<Vec<u8>>, D0Error>> {
+        let mut decoder = Decoder::new();
+        bytes.iter().map(|&b| decoder.push_byte(b)).collect()
+    }
+
+    #[test]
+    fn parse_obis_forms() {
+        assert_eq!(
+            parse_obis("1-0:1.8.0*255"),
+            Some(ObisCode::new(1, 0, 1, 8, 0, 255))
+        );
+        assert_eq!(
+            parse_obis("1-0:16.7.0"),
+            Some(ObisCode::new(1, 0, 16, 7, 0, 255))
+        );
+        assert_eq!(parse_obis("1.8.1"), Some(ObisCode::new(1, 0, 1, 8, 1, 255)));
+        // Vorwertkennzahl mit `&`, z.B. bei Landis+Gyr
+        assert_eq!(
+            parse_obis("1-0:1.8.0&01"),
+            Some(ObisCode::new(1, 0, 1, 8, 0, 1))
+        );
+        assert_eq!(parse_obis("C.1"), None);
+        assert_eq!(parse_obis("1-0:1.8.0.1"), None);
+        assert_eq!(parse_obis("1:1.8.0"), None);
+    }
+
+    #[test]
+    fn parse_number_keeps_decimals() {
+        assert_eq!(parse_number("001234.5678"), Some((12345678, 4)));
+        assert_eq!(parse_number("-000135.89"), Some((-13589, 2)));
+        assert_eq!(parse_number("+82"), Some((82, 0)));
+        assert_eq!(parse_number(".5"), Some((5, 1)));
+        assert_eq!(parse_number(""), None);
+        assert_eq!(parse_number("."), None);
+        assert_eq!(parse_number("1ESY1160408383"), None);
+    }
+
+    #[test]
+    fn parse_unit_prefixes() {
+        assert_eq!(parse_unit("kWh"), Some((Some(UNIT_WH), 3)));
+        assert_eq!(parse_unit("W"), Some((Some(UNIT_W), 0)));
+        assert_eq!(parse_unit("Mvarh"), Some((Some(UNIT_VARH), 6)));
+        assert_eq!(parse_unit("V"), Some((Some(UNIT_V), 0)));
+        // `k` allein ist keine Einheit mit Präfix
+        assert_eq!(parse_unit("k"), Some((None, 0)));
+        assert_eq!(parse_unit("m3"), Some((None, 0)));
+    }
+
+    #[test]
+    fn parse_line_kwh_to_wh() {
+        let (code, register) = parse_line("1-0:1.8.0*255(001234.5678*kWh)").unwrap();
+        assert_eq!(code, obis::ZAEHLERSTAND);
+        assert_eq!(register.value, RegisterValue::Int(12345678));
+        assert_eq!(register.scaler, Some(-1));
+        assert_eq!(register.unit, Some(UNIT_WH));
+        assert!((register.scaled().unwrap() - 1234567.8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parse_line_without_unit_or_number() {
+        let (_, register) = parse_line("1-0:96.5.5*255(82)").unwrap();
+        assert_eq!(register.value, RegisterValue::Int(82));
+        assert_eq!(register.scaler, Some(0));
+        assert_eq!(register.unit, None);
+
+        let (code, register) = parse_line("1-0:0.0.0*255(1ESY1160408383)").unwrap();
+        assert_eq!(code, ObisCode::new(1, 0, 0, 0, 0, 255));
+        assert_eq!(
+            register.value,
+            RegisterValue::Bytes(b"1ESY1160408383".to_vec())
+        );
+
+        assert!(parse_line("!").is_none());
+        assert!(parse_line("1-0:1.8.0*255(0001.5*kWh").is_none());
+    }
+
+    #[test]
+    fn parse_easymeter_telegram() {
+        let registers = parse(EASYMETER_Q3D);
+        assert_eq!(registers.len(), 9);
+        assert_eq!(
+            registers[&obis::HERSTELLER].value,
+            RegisterValue::Bytes(b"ESY5Q3DA1004 V3.04".to_vec())
+        );
+        let bezug = registers[&obis::ZAEHLERSTAND].scaled().unwrap();
+        assert!((bezug - 527032.6214).abs() < 1e-6);
+        let leistung = &registers[&ObisCode::new(1, 0, 1, 7, 255, 255)];
+        assert!((leistung.scaled().unwrap() - 135.89).abs() < 1e-9);
+        assert_eq!(leistung.unit, Some(UNIT_W));
+    }
+
+    #[test]
+    fn decoder_returns_telegram_at_end_line() {
+        let results = decode_all(EASYMETER_Q3D);
+        let telegrams: Vec<_> = results
+            .into_iter()
+            .filter_map(|r| r.ok().flatten())
+            .collect();
+        assert_eq!(telegrams, vec![EASYMETER_Q3D.to_vec()]);
+    }
+
+    #[test]
+    fn decoder_strips_parity_and_skips_garbage() {
+        // Störbytes vor dem Telegramm, Paritätsbit gesetzt (7E1 als 8N1 gelesen)
+        let mut bytes = vec![0x00, 0xff, b'x'];
+        bytes.extend(EASYMETER_Q3D.iter().map(|&b| b | 0x80));
+        let telegram = decode_all(&bytes)
+            .into_iter()
+            .find_map(|r| r.ok().flatten())
+            .unwrap();
+        assert_eq!(telegram, EASYMETER_Q3D);
+    }
+
+    #[test]
+    fn decoder_reports_incomplete_telegram() {
+        let mut bytes = EASYMETER_Q3D[..60].to_vec();
+        bytes.extend_from_slice(EASYMETER_Q3D);
+        let results = decode_all(&bytes);
+        assert!(matches!(results[60], Err(D0Error::Incomplete)));
+        assert!(matches!(results.last(), Some(Ok(Some(_)))));
+    }
+
+    #[test]
+    fn decoder_discards_overlong_telegram() {
+        let mut bytes = b"/ESY5Q3DA1004 V3.04\r\n".to_vec();
+        bytes.resize(MAX_TELEGRAM_LEN + 1, b'0');
+        let results = decode_all(&bytes);
+        assert!(matches!(results.last(), Some(Err(D0Error::TooLong))));
+    }
+}
@@ -0,0 +1,464 @@
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+use tracing::error;
+
+use crate::config::MeterConfig;
+use crate::mqtt::Topics;
+use crate::obis::{self, ObisCode, Registers};
+use crate::profile::Protocol;
+
+/// Home-Assistant-Gerät, unter dem alle Entitäten eines Zählers gruppiert werden
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub identifiers: Vec<String>,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+}
+
+/// Geräteinfo aus den Registern: Hersteller aus 129-129:199.130.3 bzw. der
+/// D0-Kennung (`/ESY5Q3DA1004` → `ESY`, Modell `Q3DA1004`), Seriennummer aus
+/// 0-0:96.1.0 bzw. 0-0:96.1.255 (D0), sonst aus 1-0:0.0.0 oder 1-0:0.0.9.
+pub fn device_info(meter: &MeterConfig, registers: &Registers) -> DeviceInfo {
+    let text = |code| registers.get(&code).and_then(|r| r.as_text());
+    let (manufacturer, ident_model) = match text(obis::HERSTELLER) {
+        // D0-Kennung: 3 Zeichen Hersteller, 1 Zeichen Baudrate, Rest Typ
+        Some(id)
+            if matches!(meter.protocol, Protocol::D0 { .. })
+                && id.len() > 4
+                && id.is_char_boundary(4) =>
+        {
+            (Some(id[..3].to_string()), Some(id[4..].trim().to_string()))
+        }
+        other => (other, None),
+    };
+    DeviceInfo {
+        identifiers: vec![format!("sml_rust_{}", meter.topics.node())],
+        name: meter.topics.node().to_string(),
+        manufacturer,
+        model: ident_model.or_else(|| meter.profile.map(|p| p.description.to_string())),
+        serial_number: text(obis::GERAETENUMMER)
+            .or_else(|| text(obis::GERAETENUMMER_D0))
+            .or_else(|| text(obis::ZAEHLERNUMMER))
+            .or_else(|| text(obis::GERAETE_ID)),
+    }
+}
+
+#[derive(Serialize)]
+struct Availability {
+    topic: String,
+}
+
+/// Discovery-Config einer Entität (`sensor` bzw. `binary_sensor`)
+#[derive(Serialize)]
+struct EntityConfig<'a> {
+    unique_id: String,
+    name: &'a str,
+    state_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_attributes_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<&'static str>,
+    device: &'a DeviceInfo,
+    /// Verfügbar nur, wenn der Dienst läuft und der Zähler Daten liefert
+    availability: [Availability; 2],
+    availability_mode: &'static str,
+}
+
+/// Eine Entität ohne die für alle gleichen Felder (Gerät, Verfügbarkeit)
+struct Entity {
+    component: &'static str,
+    object_id: String,
+    name: String,
+    state_topic: String,
+    device_class: Option<&'static str>,
+    state_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    value_template: Option<String>,
+    json_attributes_topic: Option<String>,
+    entity_category: Option<&'static str>,
+}
+
+impl Entity {
+    fn new(component: &'static str, object_id: &str, name: &str, state_topic: String) -> Self {
+        Self {
+            component,
+            object_id: object_id.to_string(),
+            name: name.to_string(),
+            state_topic,
+            device_class: None,
+            state_class: None,
+            unit: None,
+            value_template: None,
+            json_attributes_topic: None,
+            entity_category: None,
+        }
+    }
+
+    fn sensor(object_id: &str, name: &str, state_topic: String) -> Self {
+        Self::new("sensor", object_id, name, state_topic)
+    }
+
+    fn class(mut self, device_class: Option<&'static str>, state_class: &'static str) -> Self {
+        self.device_class = device_class;
+        self.state_class = Some(state_class);
+        self
+    }
+
+    fn unit(mut self, unit: &'static str) -> Self {
+        self.unit = Some(unit);
+        self
+    }
+
+    fn template(mut self, template: &str) -> Self {
+        self.value_template = Some(template.to_string());
+        self
+    }
+
+    fn diagnostic(mut self) -> Self {
+        self.entity_category = Some("diagnostic");
+        self
+    }
+
+    fn config<'a>(&'a self, topics: &'a Topics, device: &'a DeviceInfo) -> EntityConfig<'a> {
+        EntityConfig {
+            unique_id: format!("{}.{}", topics.node(), self.object_id),
+            name: &self.name,
+            state_topic: self.state_topic.clone(),
+            device_class: self.device_class,
+            state_class: self.state_class,
+            unit_of_measurement: self.unit,
+            value_template: self.value_template.clone(),
+            json_attributes_topic: self.json_attributes_topic.clone(),
+            entity_category: self.entity_category,
+            device,
+            availability: [
+                Availability {
+                    topic: topics.status().to_string(),
+                },
+                Availability {
+                    topic: topics.availability(),
+                },
+            ],
+            availability_mode: "all",
+        }
+    }
+}
+
+/// Register mit eigener Entität neben Wirkleistung, Bezug und Einspeisung
+/// (1.8.0, 2.8.0 und 16.7.0 bzw. 1.7.255 sind bereits fest angelegt).
+const FIXED_REGISTERS: &[ObisCode] = &[
+    obis::ZAEHLERSTAND,
+    obis::EINSPEISUNG,
+    obis::WIRKLEISTUNG,
+    obis::WIRKLEISTUNG_D0,
+];
+
+/// `device_class` und `state_class` nach DLMS-Einheit; Register ohne
+/// bekannte Einheit (Kennungen, Status) bekommen keine Entität.
+fn register_class(unit: u8) -> Option<(Option<&'static str>, &'static str)> {
+    let class = match unit {
+        27 => (Some("power"), "measurement"),
+        28 => (Some("apparent_power"), "measurement"),
+        29 => (Some("reactive_power"), "measurement"),
+        30 => (Some("energy"), "total_increasing"),
+        31 | 32 => (None, "total_increasing"),
+        33 => (Some("current"), "measurement"),
+        35 => (Some("voltage"), "measurement"),
+        44 => (Some("frequency"), "measurement"),
+        9 => (Some("temperature"), "measurement"),
+        8 => (None, "measurement"),
+        _ => return None,
+    };
+    Some(class)
+}
+
+fn register_object_id(code: &ObisCode) -> String {
+    let [a, b, c, d, e, _] = code.0;
+    format!("obis_{}_{}_{}_{}_{}", a, b, c, d, e)
+}
+
+/// Angekündigte Entitäten eines Zählers: Gerät und Register mit eigener Entität
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discovery {
+    pub device: DeviceInfo,
+    pub registers: Vec<ObisCode>,
+}
+
+impl Discovery {
+    pub fn new(meter: &MeterConfig, registers: &Registers) -> Self {
+        Self {
+            device: device_info(meter, registers),
+            registers: registers
+                .iter()
+                .filter(|(code, r)| {
+                    !FIXED_REGISTERS.contains(code)
+                        && r.scaled().is_some()
+                        && r.unit.and_then(register_class).is_some()
+                })
+                .map(|(code, _)| *code)
+                .collect(),
+        }
+    }
+
+    fn entities(&self, topics: &Topics, registers: &Registers) -> Vec<Entity> {
+        let stats = topics.state("sensor", "stats");
+        let zaehler = topics.state("sensor", "zaehler");
+        let mut feed = Entity::new(
+            "binary_sensor",
+            "feed",
+            "Einspeisung",
+            topics.state("binary_sensor", "feed"),
+        );
+        feed.device_class = Some("power");
+        let mut frame_errors = Entity::sensor("frame_errors", "Frame-Fehler", stats.clone())
+            .class(None, "total_increasing")
+            .template("{{ value_json.checksum_errors + value_json.decode_errors + value_json.parse_errors }}")
+            .diagnostic();
+        frame_errors.json_attributes_topic = Some(stats.clone());
+        let mut last_valid = Entity::sensor("last_valid", "Letzter gültiger Wert", stats)
+            .unit("s")
+            .template("{{ value_json.seconds_since_valid }}")
+            .diagnostic();
+        last_valid.device_class = Some("duration");
+
+        let mut entities = vec![
+            Entity::sensor(
+                "power",
+                "Wirkleistung",
+                topics.state("sensor", "wirkleistung"),
+            )
+            .class(Some("power"), "measurement")
+            .unit("W"),
+            Entity::sensor("bezug", "Netzbezug", zaehler.clone())
+                .class(Some("energy"), "total_increasing")
+                .unit("Wh")
+                .template("{{ value_json.bezug }}"),
+            Entity::sensor("einspeisung", "Netzeinspeisung", zaehler)
+                .class(Some("energy"), "total_increasing")
+                .unit("Wh")
+                .template("{{ value_json.einspeisung }}"),
+            feed,
+            // Diagnose: Frame-Statistik (siehe stats.rs)
+            frame_errors,
+            last_valid,
+        ];
+
+        // Alle weiteren Register aus `register/state` (Tarife, Leistung je Phase, ...)
+        let register_topic = topics.state("sensor", "register");
+        for code in &self.registers {
+            let Some(unit) = registers.get(code).and_then(|r| r.unit) else {
+                continue;
+            };
+            let Some((device_class, state_class)) = register_class(unit) else {
+                continue;
+            };
+            let name = code
+                .name()
+                .map(str::to_string)
+                .unwrap_or_else(|| code.to_string());
+            let mut entity =
+                Entity::sensor(&register_object_id(code), &name, register_topic.clone())
+                    .class(device_class, state_class)
+                    .template(&format!("{{{{ value_json['{}'].value }}}}", code));
+            entity.unit = obis::unit_name(unit);
+            entities.push(entity);
+        }
+        entities
+    }
+
+    /// Sendet alle Discovery-Configs (retained). Register, die seit `previous`
+    /// verschwunden sind, werden mit leerer Config aus Home Assistant entfernt.
+    pub async fn publish(
+        &self,
+        client: &AsyncClient,
+        meter: &MeterConfig,
+        registers: &Registers,
+        previous: Option<&Discovery>,
+    ) {
+        let topics = &meter.topics;
+        for entity in self.entities(topics, registers) {
+            let payload = match serde_json::to_string(&entity.config(topics, &self.device)) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!("Error serializing discovery config: {}", e);
+                    continue;
+                }
+            };
+            let _ = client
+                .publish(
+                    topics.discovery(entity.component, &entity.object_id),
+                    QoS::AtLeastOnce,
+                    true,
+                    payload,
+                )
+                .await;
+        }
+
+        for topic in self.removed_topics(topics, previous) {
+            let _ = client.publish(topic, QoS::AtLeastOnce, true, "").await;
+        }
+    }
+
+    /// Discovery-Topics der Register, die seit `previous` verschwunden sind
+    fn removed_topics(&self, topics: &Topics, previous: Option<&Discovery>) -> Vec<String> {
+        previous
+            .iter()
+            .flat_map(|p| p.registers.iter())
+            .filter(|code| !self.registers.contains(code))
+            .map(|code| topics.discovery("sensor", &register_object_id(code)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::d0;
+    use crate::obis::{Register, RegisterValue};
+    use clap::Parser;
+
+    fn meter() -> MeterConfig {
+        Config::try_parse_from(["sml_rust", "--mqtt-node-id", "haus"])
+            .unwrap()
+            .meters()
+            .unwrap()
+            .remove(0)
+    }
+
+    fn register(value: RegisterValue, unit: Option<u8>) -> Register {
+        Register {
+            value,
+            scaler: Some(0),
+            unit,
+            status: None,
+        }
+    }
+
+    /// Bezug, Wirkleistung, Leistung an L1 und L2 sowie die Gerätenummer
+    fn registers() -> Registers {
+        Registers::from([
+            (
+                obis::ZAEHLERSTAND,
+                register(RegisterValue::Int(1234), Some(30)),
+            ),
+            (
+                obis::WIRKLEISTUNG,
+                register(RegisterValue::Int(-345), Some(27)),
+            ),
+            (
+                obis::LEISTUNG_L1,
+                register(RegisterValue::Int(100), Some(27)),
+            ),
+            (
+                obis::LEISTUNG_L2,
+                register(RegisterValue::Int(-445), Some(27)),
+            ),
+            (
+                obis::GERAETENUMMER,
+                register(RegisterValue::Bytes(b"1EMH0012345678".to_vec()), None),
+            ),
+        ])
+    }
+
+    #[test]
+    fn entities_from_registers() {
+        let meter = meter();
+        let registers = registers();
+        let discovery = Discovery::new(&meter, &registers);
+        // Feste Entitäten und Kennungen ohne Einheit bekommen keine eigene Register-Entität
+        assert_eq!(discovery.registers, [obis::LEISTUNG_L1, obis::LEISTUNG_L2]);
+        assert_eq!(
+            discovery.device.serial_number.as_deref(),
+            Some("1EMH0012345678")
+        );
+
+        let entities = discovery.entities(&meter.topics, &registers);
+        let ids: Vec<&str> = entities.iter().map(|e| e.object_id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                "power",
+                "bezug",
+                "einspeisung",
+                "feed",
+                "frame_errors",
+                "last_valid",
+                "obis_1_0_36_7_0",
+                "obis_1_0_56_7_0",
+            ]
+        );
+
+        let l1 = &entities[6];
+        assert_eq!(l1.component, "sensor");
+        assert_eq!(l1.name, "Leistung an L1");
+        assert_eq!(l1.unit, Some("W"));
+        assert_eq!(l1.device_class, Some("power"));
+        assert_eq!(l1.state_topic, meter.topics.state("sensor", "register"));
+        let config = serde_json::to_value(l1.config(&meter.topics, &discovery.device)).unwrap();
+        assert_eq!(config["unique_id"], "haus.obis_1_0_36_7_0");
+        assert_eq!(
+            config["value_template"],
+            format!("{{{{ value_json['{}'].value }}}}", obis::LEISTUNG_L1)
+        );
+        assert_eq!(config["device"]["identifiers"][0], "sml_rust_haus");
+    }
+
+    #[test]
+    fn serial_number_from_device_number() {
+        let meter = meter();
+        let mut registers = registers();
+        registers.insert(
+            obis::ZAEHLERNUMMER,
+            register(RegisterValue::Bytes(b"4711".to_vec()), None),
+        );
+        // 0-0:96.1.0 hat Vorrang vor der Eigentumsnummer
+        assert_eq!(
+            device_info(&meter, &registers).serial_number.as_deref(),
+            Some("1EMH0012345678")
+        );
+        registers.remove(&obis::GERAETENUMMER);
+        assert_eq!(
+            device_info(&meter, &registers).serial_number.as_deref(),
+            Some("4711")
+        );
+
+        // EasyMeter Q3D: Gerätenummer als 0-0:96.1.255
+        let mut registers = d0::parse(d0::tests::EASYMETER_Q3D);
+        registers.remove(&obis::ZAEHLERNUMMER);
+        let device = device_info(&meter, &registers);
+        assert_eq!(device.serial_number.as_deref(), Some("1ESY1160408383"));
+    }
+
+    #[test]
+    fn removed_registers_cleared() {
+        let meter = meter();
+        let mut registers = registers();
+        let previous = Discovery::new(&meter, &registers);
+        assert!(previous.removed_topics(&meter.topics, None).is_empty());
+        assert!(previous
+            .removed_topics(&meter.topics, Some(&previous))
+            .is_empty());
+
+        registers.remove(&obis::LEISTUNG_L2);
+        registers.insert(obis::LEISTUNG_L3, register(RegisterValue::Int(7), Some(27)));
+        let discovery = Discovery::new(&meter, &registers);
+        assert_eq!(
+            discovery.removed_topics(&meter.topics, Some(&previous)),
+            [meter.topics.discovery("sensor", "obis_1_0_56_7_0")]
+        );
+    }
+}
@@ -0,0 +1,384 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Request, Uri};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::sink::{Reading, Sink};
+
+/// Zeitlimit für einen Schreibvorgang
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Längste Wartezeit zwischen zwei Versuchen nach Fehlern
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Größe eines UDP-Pakets, der InfluxDB-UDP-Listener liest max. 64 KiB
+const UDP_PACKET_SIZE: usize = 8192;
+
+/// Schreibt jeden Messwert als Zeile im InfluxDB-Line-Protocol, z.B.
+/// `sml,meter=haus bezug=1234.5,einspeisung=12.3,wirkleistung=-250.0,feed_in=true 1700000000000000000`.
+/// Gesendet wird gesammelt von `run_influx_writer`.
+pub struct InfluxSink {
+    measurement: String,
+    tx: mpsc::Sender<String>,
+}
+
+impl InfluxSink {
+    pub fn new(config: &Config, tx: mpsc::Sender<String>) -> Self {
+        Self {
+            measurement: escape_measurement(&config.influx_measurement),
+            tx,
+        }
+    }
+
+    /// Zeile im Line-Protocol, `None` außerhalb des Nanosekunden-Bereichs
+    fn line(&self, reading: &Reading<'_>) -> Option<String> {
+        let timestamp = reading.time.timestamp_nanos_opt()?;
+        Some(format!(
+            "{},meter={} bezug={},einspeisung={},wirkleistung={},feed_in={} {}",
+            self.measurement,
+            escape_tag(&reading.meter.name),
+            reading.bezug,
+            reading.einspeisung,
+            reading.wirkleistung,
+            reading.feed_in,
+            timestamp
+        ))
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxSink {
+    fn name(&self) -> &'static str {
+        "influx"
+    }
+
+    async fn reading(&mut self, reading: &Reading<'_>) {
+        let Some(line) = self.line(reading) else {
+            return;
+        };
+        // Nicht warten: bei vollem Kanal hängt der Writer ohnehin hinterher
+        if self.tx.try_send(line).is_err() {
+            warn!("InfluxDB writer is not keeping up, dropping reading");
+        }
+    }
+}
+
+/// Measurement: Komma und Leerzeichen maskieren (`=` bleibt, wie es ist)
+fn escape_measurement(s: &str) -> String {
+    s.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+/// Tag-Werte: zusätzlich `=` maskieren
+fn escape_tag(s: &str) -> String {
+    escape_measurement(s).replace('=', "\\=")
+}
+
+/// Kanal zwischen den `InfluxSink`s aller Zähler und dem Writer
+pub fn channel(config: &Config) -> (mpsc::Sender<String>, mpsc::Receiver<String>) {
+    mpsc::channel(config.influx_batch_size.max(1) * 2)
+}
+
+/// Ziel aus `--influx-url`
+#[derive(Debug, PartialEq)]
+pub enum Endpoint {
+    Http(Uri),
+    /// `host:port` des UDP-Listeners
+    Udp(String),
+}
+
+/// Prüft `--influx-url`: `http(s)://host[:port]/pfad` oder `udp://host:port`
+pub fn parse_url(url: &str) -> anyhow::Result<Endpoint> {
+    if let Some(addr) = url.strip_prefix("udp://") {
+        return match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(Endpoint::Udp(addr.to_string()))
+            }
+            _ => anyhow::bail!("UDP address must be udp://host:port: {}", url),
+        };
+    }
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| anyhow::anyhow!("invalid URL {}: {}", url, e))?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+        anyhow::bail!("URL must start with http://, https:// or udp://: {}", url);
+    }
+    Ok(Endpoint::Http(uri))
+}
+
+enum Target {
+    Http {
+        client: Box<Client<HttpsConnector<HttpConnector>, Full<Bytes>>>,
+        uri: Uri,
+        token: Option<String>,
+    },
+    Udp {
+        socket: UdpSocket,
+    },
+}
+
+/// Fehler beim Schreiben: wiederholen oder Zeilen verwerfen
+enum WriteError {
+    Retry(String),
+    /// Vom Server abgelehnt (4xx), ein neuer Versuch ändert nichts
+    Rejected(String),
+}
+
+impl Target {
+    async fn connect(config: &Config, endpoint: &Endpoint) -> anyhow::Result<Self> {
+        let uri = match endpoint {
+            Endpoint::Udp(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.connect(addr).await?;
+                return Ok(Target::Udp { socket });
+            }
+            Endpoint::Http(uri) => uri.clone(),
+        };
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()?
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Target::Http {
+            client: Box::new(Client::builder(TokioExecutor::new()).build(https)),
+            uri,
+            token: config.influx_token.clone(),
+        })
+    }
+
+    async fn write(&self, lines: &[String]) -> Result<(), WriteError> {
+        match self {
+            Target::Http { client, uri, token } => {
+                let mut request =
+                    Request::post(uri.clone()).header(CONTENT_TYPE, "text/plain; charset=utf-8");
+                if let Some(token) = token {
+                    request = request.header(AUTHORIZATION, format!("Token {}", token));
+                }
+                let body = Full::new(Bytes::from(lines.join("\n")));
+                let request = request
+                    .body(body)
+                    .map_err(|e| WriteError::Rejected(e.to_string()))?;
+                let response = tokio::time::timeout(WRITE_TIMEOUT, client.request(request))
+                    .await
+                    .map_err(|_| WriteError::Retry("timeout".to_string()))?
+                    .map_err(|e| WriteError::Retry(e.to_string()))?;
+                let status = response.status();
+                if status.is_success() {
+                    return Ok(());
+                }
+                let body = match response.into_body().collect().await {
+                    Ok(body) => String::from_utf8_lossy(&body.to_bytes()).into_owned(),
+                    Err(_) => String::new(),
+                };
+                let message = format!("{} {}", status, body.trim());
+                if status.is_client_error() && status.as_u16() != 429 {
+                    Err(WriteError::Rejected(message))
+                } else {
+                    Err(WriteError::Retry(message))
+                }
+            }
+            Target::Udp { socket } => {
+                let mut packet = String::new();
+                for line in lines {
+                    if !packet.is_empty() && packet.len() + line.len() + 1 > UDP_PACKET_SIZE {
+                        send_udp(socket, &packet).await?;
+                        packet.clear();
+                    }
+                    packet.push_str(line);
+                    packet.push('\n');
+                }
+                if !packet.is_empty() {
+                    send_udp(socket, &packet).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn send_udp(socket: &UdpSocket, packet: &str) -> Result<(), WriteError> {
+    socket
+        .send(packet.as_bytes())
+        .await
+        .map(|_| ())
+        .map_err(|e| WriteError::Retry(e.to_string()))
+}
+
+/// Sammelt die Zeilen aller Zähler und schreibt sie alle `--influx-flush-interval`
+/// bzw. sobald `--influx-batch-size` Zeilen vorliegen. Nach Fehlern bleiben die
+/// Zeilen gepuffert (höchstens `--influx-buffer-size`, die ältesten fallen
+/// heraus) und werden mit wachsendem Abstand erneut gesendet. Das gilt auch,
+/// wenn schon der Verbindungsaufbau scheitert. Endet, wenn alle
+/// Eingabeschleifen beendet sind.
+pub async fn run_influx_writer(config: Config, url: String, mut rx: mpsc::Receiver<String>) {
+    // Bereits von `Config::validate` geprüft
+    let endpoint = match parse_url(&url) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("InfluxDB output disabled: {}", e);
+            return;
+        }
+    };
+    info!("Writing readings to InfluxDB at {}", url);
+    let mut target = None;
+
+    let batch_size = config.influx_batch_size.max(1);
+    let mut buffer: VecDeque<String> = VecDeque::new();
+    let mut backoff = config.influx_flush_interval;
+    let mut next_try = tokio::time::Instant::now();
+    let mut interval = tokio::time::interval(config.influx_flush_interval);
+
+    loop {
+        let mut flush = tokio::select! {
+            line = rx.recv() => match line {
+                Some(line) => {
+                    buffer.push_back(line);
+                    if buffer.len() > config.influx_buffer_size {
+                        buffer.pop_front();
+                        warn!("InfluxDB buffer full, dropping oldest reading");
+                    }
+                    buffer.len() >= batch_size
+                }
+                None => break,
+            },
+            _ = interval.tick() => true,
+        };
+        // Nach einem Fehler erst nach Ablauf der Wartezeit erneut versuchen
+        flush &= tokio::time::Instant::now() >= next_try;
+        if flush && !buffer.is_empty() {
+            match flush_buffer(&mut target, &config, &endpoint, &mut buffer, batch_size).await {
+                Ok(()) => backoff = config.influx_flush_interval,
+                Err(e) => {
+                    warn!(
+                        "InfluxDB write failed ({} readings buffered), retrying in {:?}: {}",
+                        buffer.len(),
+                        backoff,
+                        e
+                    );
+                    next_try = tokio::time::Instant::now() + backoff;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    // Beim Beenden noch einmal versuchen, was sich angesammelt hat
+    if !buffer.is_empty() {
+        if let Err(e) = flush_buffer(&mut target, &config, &endpoint, &mut buffer, batch_size).await
+        {
+            warn!(
+                "InfluxDB: {} readings lost on shutdown: {}",
+                buffer.len(),
+                e
+            );
+        }
+    }
+}
+
+/// Schreibt den Puffer in Blöcken von `batch_size` Zeilen, vorher wird bei
+/// Bedarf die Verbindung aufgebaut
+async fn flush_buffer(
+    target: &mut Option<Target>,
+    config: &Config,
+    endpoint: &Endpoint,
+    buffer: &mut VecDeque<String>,
+    batch_size: usize,
+) -> Result<(), String> {
+    let target = match target {
+        Some(target) => target,
+        None => target.insert(
+            Target::connect(config, endpoint)
+                .await
+                .map_err(|e| format!("connecting: {}", e))?,
+        ),
+    };
+    while !buffer.is_empty() {
+        let n = buffer.len().min(batch_size);
+        let batch: Vec<String> = buffer.iter().take(n).cloned().collect();
+        match target.write(&batch).await {
+            Ok(()) => {
+                buffer.drain(..n);
+            }
+            Err(WriteError::Rejected(e)) => {
+                error!("InfluxDB rejected {} readings: {}", n, e);
+                buffer.drain(..n);
+            }
+            Err(WriteError::Retry(e)) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::obis::Registers;
+    use chrono::{Local, TimeZone};
+    use clap::Parser;
+
+    #[test]
+    fn escape_special_characters() {
+        assert_eq!(escape_measurement("sml data,v2"), "sml\\ data\\,v2");
+        assert_eq!(escape_measurement("a=b"), "a=b");
+        assert_eq!(escape_tag("haus, keller=1"), "haus\\,\\ keller\\=1");
+        assert_eq!(escape_tag("haus"), "haus");
+    }
+
+    #[test]
+    fn line_protocol() {
+        let config = Config::try_parse_from([
+            "sml_rust",
+            "--influx-measurement",
+            "sml data",
+            "--meter",
+            "haus=/dev/ttyUSB0",
+        ])
+        .unwrap();
+        let meter = config.meters().unwrap().remove(0);
+        let sink = InfluxSink::new(&config, mpsc::channel(1).0);
+        let registers = Registers::new();
+        let reading = Reading {
+            meter: &meter,
+            time: Local.timestamp_opt(1700000000, 500).unwrap(),
+            bezug: 1234.5,
+            einspeisung: 12.0,
+            wirkleistung: -250.25,
+            feed_in: true,
+            feed_in_changed: false,
+            registers: &registers,
+        };
+        assert_eq!(
+            sink.line(&reading).unwrap(),
+            "sml\\ data,meter=haus bezug=1234.5,einspeisung=12,\
+             wirkleistung=-250.25,feed_in=true 1700000000000000500"
+        );
+    }
+
+    #[test]
+    fn parse_url_endpoints() {
+        assert_eq!(
+            parse_url("udp://influx:8089").unwrap(),
+            Endpoint::Udp("influx:8089".to_string())
+        );
+        assert_eq!(
+            parse_url("http://influx:8086/api/v2/write?org=o&bucket=b").unwrap(),
+            Endpoint::Http(Uri::from_static(
+                "http://influx:8086/api/v2/write?org=o&bucket=b"
+            ))
+        );
+        assert!(parse_url("https://vm.example.org/write").is_ok());
+        assert!(parse_url("udp://influx").is_err());
+        assert!(parse_url("udp://:8089").is_err());
+        assert!(parse_url("influx:8086/write").is_err());
+        assert!(parse_url("ftp://influx/write").is_err());
+        assert!(parse_url("http://").is_err());
+    }
+}
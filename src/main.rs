@@ -1,16 +1,35 @@
+mod capture;
+mod command;
 mod config;
+mod d0;
+mod discovery;
+mod influx;
+mod metrics;
 mod model;
 mod mqtt;
+mod obis;
+mod persist;
+mod profile;
+mod queue;
+#[cfg(feature = "rrd")]
 mod rrd;
+mod sink;
 mod sml;
+mod source;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod watchdog;
 mod web;
 
 use crate::config::Config;
-use crate::model::AppState;
+use crate::model::{AppState, MeterState};
+use crate::sink::Sink;
 //use anyhow::Ok;
+#[cfg(feature = "rrd")]
 use ::rrd::ops::version::librrd_version;
-use clap::Parser;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::broadcast;
 use tokio_util::sync::CancellationToken;
@@ -23,7 +42,6 @@ async fn main() -> anyhow::Result<()> {
 
     let token = CancellationToken::new();
     let cloned_token = token.clone();
-    let graph_token = token.clone();
 
     tokio::spawn(async move {
         let mut sigterm = signal(SignalKind::terminate()).unwrap();
@@ -32,44 +50,180 @@ async fn main() -> anyhow::Result<()> {
         cloned_token.cancel();
     });
 
-    let config = Config::parse();
-    info!("Starting SML Service. Serial port: {}", config.serial_port);
+    let config = Config::load()?;
+    let meters = config.meters()?;
+    for meter in &meters {
+        info!(
+            "Starting SML Service. Meter {}: {:?}",
+            meter.name, meter.input
+        );
+    }
 
-    info!("Librrd version {}", librrd_version());
-    rrd::ensure_rrd(&config);
+    #[cfg(feature = "rrd")]
+    if config.rrd_enabled {
+        info!("Librrd version {}", librrd_version());
+        for meter in &meters {
+            rrd::ensure_rrd(meter, &config);
+        }
+    }
+    #[cfg(not(feature = "rrd"))]
+    if config.rrd_enabled {
+        info!("Built without RRD support, no graphs");
+    }
 
     let (tx, _rx) = broadcast::channel(100);
     let shared_state = Arc::new(Mutex::new(AppState {
-        wirkleistung: 0.0,
-        zaehlerstand_diff: 0.0,
-        einspeisung: 0.0,
-        einspeisung_sts: false,
+        meters: meters
+            .iter()
+            .map(|m| MeterState {
+                name: m.name.clone(),
+                images: m.image_url.clone(),
+                ..Default::default()
+            })
+            .collect(),
         tx,
+        mqtt_connected: config.mqtt_enabled.then_some(false),
     }));
 
-    let mqtt_client = mqtt::init_mqtt(&config).await;
+    // MQTT-Befehle an die Eingabeschleifen, ein Kanal je Zähler
+    let (command_senders, command_receivers): (Vec<_>, Vec<_>) =
+        meters.iter().map(|_| command::meter_channel()).unzip();
+    let commands = command::CommandHandler::new(
+        meters.iter().cloned().zip(command_senders).collect(),
+        &config.mqtt_response_topic,
+    );
 
-    // A) Serial Reader
-    let state_serial = shared_state.clone();
-    let config_serial = config.clone();
-    let client_serial = mqtt_client.clone();
-    tokio::spawn(async move {
-        sml::run_serial_loop(config_serial, state_serial, client_serial, token).await;
-    });
+    // MQTT ist optional, ohne Broker laufen nur RRD und Webserver
+    let mqtt = if config.mqtt_enabled {
+        // Zählerstände bei getrennter Verbindung puffern
+        let mqtt_queue =
+            queue::OfflineQueue::open(config.mqtt_queue_path.clone(), config.mqtt_queue_size);
+        let mqtt_client = mqtt::init_mqtt(
+            &config,
+            &meters,
+            shared_state.clone(),
+            commands,
+            mqtt_queue.clone(),
+        )
+        .await?;
+        Some((mqtt_client, mqtt_queue))
+    } else {
+        info!("MQTT disabled");
+        None
+    };
+    let mqtt_client = mqtt.as_ref().map(|(client, _)| client.clone());
 
-    // B) RRD Graph Generator
-    let config_rrd = config.clone();
-    tokio::spawn(async move {
-        rrd::run_graph_loop(config_rrd, graph_token).await;
+    // InfluxDB: ein Writer für alle Zähler
+    let mut influx_handle = None;
+    let influx_tx = config.influx_url.clone().map(|url| {
+        let (tx, rx) = influx::channel(&config);
+        let config_influx = config.clone();
+        influx_handle = Some(tokio::spawn(async move {
+            influx::run_influx_writer(config_influx, url, rx).await;
+        }));
+        tx
     });
 
+    // SQLite: ein Writer-Thread für alle Zähler
+    #[cfg(not(feature = "sqlite"))]
+    if config.sqlite_path.is_some() {
+        anyhow::bail!("SQLite storage needs a build with `--features sqlite`");
+    }
+    #[cfg(feature = "sqlite")]
+    let (sqlite_tx, sqlite_handle) = config
+        .sqlite_path
+        .as_deref()
+        .map(sqlite::start)
+        .transpose()?
+        .unzip();
+
+    let mut input_handles = Vec::new();
+    for (meter, commands_serial) in meters.iter().zip(command_receivers) {
+        // A) SML Reader (Serial / TCP / Datei / stdin), je Zähler
+        let state_serial = shared_state.clone();
+        let config_serial = config.clone();
+        let meter_serial = meter.clone();
+        let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+        #[cfg(feature = "rrd")]
+        if config.rrd_enabled {
+            sinks.push(Box::new(rrd::RrdSink));
+        }
+        if let Some((client, queue)) = &mqtt {
+            sinks.push(Box::new(mqtt::MqttSink::new(queue::MqttPublisher::new(
+                client.clone(),
+                queue.clone(),
+            ))));
+        }
+        if let Some(tx) = &influx_tx {
+            sinks.push(Box::new(influx::InfluxSink::new(&config, tx.clone())));
+        }
+        #[cfg(feature = "sqlite")]
+        if let Some(tx) = &sqlite_tx {
+            sinks.push(Box::new(sqlite::SqliteSink::new(tx.clone())));
+        }
+        info!(
+            "Meter {}: outputs {}",
+            meter.name,
+            sinks
+                .iter()
+                .map(|s| s.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let input_token = token.clone();
+        input_handles.push(tokio::spawn(async move {
+            sml::run_input_loop(
+                config_serial,
+                meter_serial,
+                state_serial,
+                sinks,
+                commands_serial,
+                input_token,
+            )
+            .await;
+        }));
+
+        // Watchdog: Zähler ohne Daten als nicht verfügbar melden
+        let meter_watchdog = meter.clone();
+        let state_watchdog = shared_state.clone();
+        let client_watchdog = mqtt_client.clone();
+        let watchdog_token = token.clone();
+        tokio::spawn(async move {
+            watchdog::run_watchdog(
+                meter_watchdog,
+                state_watchdog,
+                client_watchdog,
+                watchdog_token,
+            )
+            .await;
+        });
+
+        // B) RRD Graph Generator
+        #[cfg(feature = "rrd")]
+        if config.rrd_enabled {
+            let meter_rrd = meter.clone();
+            let graph_interval = config.graph_interval;
+            let graph_token = token.clone();
+            tokio::spawn(async move {
+                rrd::run_graph_loop(meter_rrd, graph_interval, graph_token).await;
+            });
+        }
+    }
+
     // C) Webserver
     let config_web = config.clone();
+    #[cfg(feature = "sqlite")]
+    let history = config
+        .sqlite_path
+        .clone()
+        .map(|path| sqlite::History::new(path, meters.iter().map(|m| m.name.clone()).collect()));
     let server_handle = tokio::spawn(async move {
         if let Err(e) = web::start_server(
             &config_web.server_addr,
             &config_web.image_output_path,
             shared_state,
+            #[cfg(feature = "sqlite")]
+            history,
         )
         .await
         {
@@ -89,6 +243,38 @@ async fn main() -> anyhow::Result<()> {
     info!("Shutting down application...");
     server_handle.abort();
 
-    rrd::save_rrd_on_shutdown(&config);
+    // Eingabeschleifen beenden lassen, damit der Zustand gesichert wird
+    token.cancel();
+    if let Some(client) = &mqtt_client {
+        mqtt::publish_offline(client, &config).await;
+    }
+    let stopped = tokio::time::timeout(Duration::from_secs(5), async {
+        for handle in input_handles {
+            let _ = handle.await;
+        }
+        // Der Writer schreibt den Rest, sobald alle Sinks weg sind
+        drop(influx_tx);
+        if let Some(handle) = influx_handle {
+            let _ = handle.await;
+        }
+        #[cfg(feature = "sqlite")]
+        {
+            drop(sqlite_tx);
+            if let Some(handle) = sqlite_handle {
+                let _ = tokio::task::spawn_blocking(move || handle.join()).await;
+            }
+        }
+    })
+    .await;
+    if stopped.is_err() {
+        error!("Input loop did not stop in time, state may not be saved");
+    }
+
+    #[cfg(feature = "rrd")]
+    if config.rrd_enabled {
+        for meter in &meters {
+            rrd::save_rrd_on_shutdown(meter);
+        }
+    }
     Ok(())
 }
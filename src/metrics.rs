@@ -0,0 +1,308 @@
+use std::fmt::Write;
+
+use crate::model::AppState;
+use crate::obis;
+#[cfg(feature = "rrd")]
+use crate::rrd;
+use crate::stats::FrameStats;
+
+/// Zähler aus `FrameStats` mit Name und Beschreibung
+type FrameCounter = (&'static str, &'static str, fn(&FrameStats) -> u64);
+
+const FRAME_COUNTERS: &[FrameCounter] = &[
+    (
+        "sml_frames_received_total",
+        "Decoded frames (SML transport or D0 telegram)",
+        |s| s.frames_received,
+    ),
+    (
+        "sml_checksum_errors_total",
+        "Frames with CRC mismatch",
+        |s| s.checksum_errors,
+    ),
+    (
+        "sml_decode_errors_total",
+        "Other transport protocol errors",
+        |s| s.decode_errors,
+    ),
+    (
+        "sml_discarded_bytes_total",
+        "Bytes discarded outside of a frame",
+        |s| s.discarded_bytes,
+    ),
+    (
+        "sml_parse_errors_total",
+        "Decoded frames whose content could not be parsed",
+        |s| s.parse_errors,
+    ),
+    (
+        "sml_unknown_messages_total",
+        "SML messages other than Open, Close and GetListResponse",
+        |s| s.unknown_messages,
+    ),
+    (
+        "sml_valid_readings_total",
+        "Frames with meter reading or active power",
+        |s| s.valid_readings,
+    ),
+];
+
+/// Prometheus-Textformat (Version 0.0.4) für `/metrics`
+pub fn render(state: &AppState) -> String {
+    let mut out = Metrics::default();
+
+    out.family(
+        "sml_power_watts",
+        "gauge",
+        "Current active power, negative while feeding in",
+    );
+    for m in state.meters.iter().filter(|m| m.stats.valid_readings > 0) {
+        out.sample("sml_power_watts", &[("meter", &m.name)], m.wirkleistung);
+    }
+    out.family(
+        "sml_energy_import_watthours_total",
+        "counter",
+        "Imported energy (OBIS 1.8.0)",
+    );
+    for m in state.meters.iter().filter(|m| m.stats.valid_readings > 0) {
+        out.sample(
+            "sml_energy_import_watthours_total",
+            &[("meter", &m.name)],
+            m.zaehlerstand,
+        );
+    }
+    out.family(
+        "sml_energy_export_watthours_total",
+        "counter",
+        "Exported energy, from OBIS 2.8.0 or integrated from negative power",
+    );
+    for m in state.meters.iter().filter(|m| m.stats.valid_readings > 0) {
+        out.sample(
+            "sml_energy_export_watthours_total",
+            &[("meter", &m.name)],
+            m.einspeisung,
+        );
+    }
+    out.family("sml_feed_in", "gauge", "1 while feed-in is detected");
+    for m in state.meters.iter().filter(|m| m.stats.valid_readings > 0) {
+        out.sample(
+            "sml_feed_in",
+            &[("meter", &m.name)],
+            m.einspeisung_sts as u8 as f64,
+        );
+    }
+
+    out.family("sml_phase_power_watts", "gauge", "Active power per phase");
+    for m in &state.meters {
+        let phases = ["L1", "L2", "L3"].into_iter();
+        for (phase, value) in phases.zip(obis::leistung_phasen(&m.registers)) {
+            if let Some(value) = value {
+                out.sample(
+                    "sml_phase_power_watts",
+                    &[("meter", &m.name), ("phase", phase)],
+                    value,
+                );
+            }
+        }
+    }
+    out.family(
+        "sml_register_value",
+        "gauge",
+        "Last value of every numeric register the meter reports",
+    );
+    for m in &state.meters {
+        for (code, register) in &m.registers {
+            let Some(value) = register.scaled() else {
+                continue;
+            };
+            let obis = code.to_string();
+            out.sample(
+                "sml_register_value",
+                &[
+                    ("meter", &m.name),
+                    ("obis", &obis),
+                    ("unit", register.unit_name().unwrap_or("")),
+                ],
+                value,
+            );
+        }
+    }
+
+    out.family(
+        "sml_meter_available",
+        "gauge",
+        "1 while the meter delivers valid readings within --stale-timeout",
+    );
+    for m in &state.meters {
+        out.sample(
+            "sml_meter_available",
+            &[("meter", &m.name)],
+            m.available as u8 as f64,
+        );
+    }
+    out.family(
+        "sml_seconds_since_valid_reading",
+        "gauge",
+        "Age of the last valid reading",
+    );
+    for m in &state.meters {
+        if let Some(age) = m.stats.seconds_since_valid() {
+            out.sample(
+                "sml_seconds_since_valid_reading",
+                &[("meter", &m.name)],
+                age,
+            );
+        }
+    }
+
+    // Frame-Statistik (siehe stats.rs)
+    for (name, help, value) in FRAME_COUNTERS {
+        out.family(name, "counter", help);
+        for m in &state.meters {
+            out.sample(name, &[("meter", &m.name)], value(&m.stats) as f64);
+        }
+    }
+
+    // Dienst
+    #[cfg(feature = "rrd")]
+    out.family(
+        "sml_rrd_update_errors_total",
+        "counter",
+        "Failed RRD updates",
+    );
+    #[cfg(feature = "rrd")]
+    out.sample(
+        "sml_rrd_update_errors_total",
+        &[],
+        rrd::update_errors() as f64,
+    );
+    out.family(
+        "sml_sse_subscribers",
+        "gauge",
+        "Connected browsers on /events",
+    );
+    out.sample("sml_sse_subscribers", &[], state.tx.receiver_count() as f64);
+    if let Some(connected) = state.mqtt_connected {
+        out.family(
+            "sml_mqtt_connected",
+            "gauge",
+            "1 while connected to the MQTT broker",
+        );
+        out.sample("sml_mqtt_connected", &[], connected as u8 as f64);
+    }
+
+    out.0
+}
+
+#[derive(Default)]
+struct Metrics(String);
+
+impl Metrics {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+/// Label-Werte: Backslash, Anführungszeichen und Zeilenumbruch maskieren
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::MeterState;
+    use crate::obis::{Register, RegisterValue, Registers};
+    use tokio::sync::broadcast;
+
+    fn state() -> AppState {
+        let register = |value, unit| Register {
+            value,
+            scaler: Some(0),
+            unit,
+            status: None,
+        };
+        let mut haus = MeterState {
+            name: "haus".to_string(),
+            wirkleistung: -250.1,
+            zaehlerstand: 123456789.1,
+            einspeisung: 12345678.9,
+            einspeisung_sts: true,
+            available: true,
+            registers: Registers::from([
+                (
+                    obis::LEISTUNG_L1,
+                    register(RegisterValue::Int(100), Some(27)),
+                ),
+                (
+                    obis::HERSTELLER,
+                    register(RegisterValue::Bytes(b"EMH".to_vec()), None),
+                ),
+            ]),
+            ..Default::default()
+        };
+        haus.stats.valid_readings = 3;
+        // Noch ohne gültigen Messwert: keine Leistung und Zählerstände
+        let keller = MeterState {
+            name: "keller".to_string(),
+            ..Default::default()
+        };
+        AppState {
+            meters: vec![haus, keller],
+            tx: broadcast::channel(16).0,
+            mqtt_connected: Some(true),
+        }
+    }
+
+    #[test]
+    fn render_text_format() {
+        let text = render(&state());
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in [
+            "# HELP sml_power_watts Current active power, negative while feeding in",
+            "# TYPE sml_power_watts gauge",
+            // f64: keine Rundungsfehler bei Leistung und großen Zählerständen
+            "sml_power_watts{meter=\"haus\"} -250.1",
+            "sml_energy_import_watthours_total{meter=\"haus\"} 123456789.1",
+            "sml_energy_export_watthours_total{meter=\"haus\"} 12345678.9",
+            "sml_feed_in{meter=\"haus\"} 1",
+            "sml_phase_power_watts{meter=\"haus\",phase=\"L1\"} 100",
+            "sml_register_value{meter=\"haus\",obis=\"1-0:36.7.0\",unit=\"W\"} 100",
+            "sml_meter_available{meter=\"haus\"} 1",
+            "sml_meter_available{meter=\"keller\"} 0",
+            "sml_valid_readings_total{meter=\"haus\"} 3",
+            "sml_valid_readings_total{meter=\"keller\"} 0",
+            "sml_sse_subscribers 0",
+            "sml_mqtt_connected 1",
+        ] {
+            assert!(lines.contains(&expected), "missing: {}", expected);
+        }
+        assert!(!text.contains("sml_power_watts{meter=\"keller\"}"));
+        // Register ohne Zahlenwert (Herstellerkennung) fehlen
+        assert!(!text.contains("129-129:199.130.3"));
+        // Jede Familie genau einmal
+        let types: Vec<&&str> = lines.iter().filter(|l| l.starts_with("# TYPE")).collect();
+        let unique: std::collections::HashSet<&&str> = types.iter().copied().collect();
+        assert_eq!(types.len(), unique.len());
+    }
+
+    #[test]
+    fn escape_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
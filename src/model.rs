@@ -1,52 +1,110 @@
+use crate::obis::{ObisCode, Registers};
+use crate::persist::SavedState;
+use crate::stats::FrameStats;
+use chrono::{DateTime, Local};
 use serde::Serialize;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use tokio::sync::broadcast;
 
 #[derive(Clone, Serialize, Debug)]
 pub struct SseData {
+    pub meter: String,
     pub time: String,
-    pub value: f32,        // Aktuelle Leistung (Watt)
-    pub value2: f32,       // Differenz (für Chart)
-    pub total_energy: f64, // NEU: Zählerstand Total (kWh)
+    pub value: f64,        // Aktuelle Leistung (Watt)
+    pub value2: f64,       // Differenz (für Chart)
+    pub total_energy: f64, // NEU: Zählerstand Total (Wh)
     pub is_feed_in: bool,  // NEU: Status Einspeisung
+    pub available: bool,   // false, wenn der Watchdog keine Daten mehr sieht
+    pub registers: Registers,
 }
 
-pub struct AppState {
-    pub wirkleistung: f32,
-    pub zaehlerstand_diff: f32,
-    pub einspeisung: f32,
+/// Zuletzt gemeldete Werte eines Zählers
+#[derive(Clone, Serialize, Debug, Default)]
+pub struct MeterState {
+    pub name: String,
+    /// URL-Pfad der Graphen relativ zu `/images`
+    pub images: String,
+    pub wirkleistung: f64,
+    pub zaehlerstand: f64,
+    pub zaehlerstand_diff: f64,
+    pub einspeisung: f64,
     pub einspeisung_sts: bool,
+    /// Vom Watchdog gesetzt: gültige Daten innerhalb von `--stale-timeout`
+    pub available: bool,
+    pub registers: Registers,
+    pub stats: FrameStats,
+}
+
+pub struct AppState {
+    /// Alle Zähler in der konfigurierten Reihenfolge
+    pub meters: Vec<MeterState>,
     pub tx: broadcast::Sender<SseData>,
+    /// Verbindung zum Broker, `None` ohne MQTT
+    pub mqtt_connected: Option<bool>,
+}
+
+impl AppState {
+    pub fn meter_mut(&mut self, name: &str) -> Option<&mut MeterState> {
+        self.meters.iter_mut().find(|m| m.name == name)
+    }
 }
 
 pub type SharedAppState = Arc<Mutex<AppState>>;
 
+/// Messwerte in physikalischen Einheiten (W bzw. Wh)
 pub struct SensorData {
-    pub wirkleistung: i32,
-    pub wirkleistung_alt: i32,
-    pub zaehlerstand: u64,
-    pub zaehlerstand_alt: u64,
-    pub zaehlerstand_diff: u32,
-    pub einspeisung: u64,
+    pub wirkleistung: f64,
+    pub wirkleistung_alt: f64,
+    pub zaehlerstand: f64,
+    pub zaehlerstand_alt: f64,
+    pub zaehlerstand_diff: f64,
+    pub einspeisung: f64,
+    /// `einspeisung` stammt aus dem Register 2.8.0 statt aus der Integration
+    pub einspeisung_vom_zaehler: bool,
     pub einspeisung_sts: bool,
-    pub last_integration_time: Option<Instant>,
-    pub last_mqtt_publish: Option<Instant>,
+    /// Seit wann die Schwelle für einen Wechsel von `einspeisung_sts` erreicht ist
+    pub feed_in_pending_since: Option<Instant>,
+    /// Ablesezeitpunkt des letzten Messwerts, bei `--replay` aus dem Mitschnitt
+    pub last_integration_time: Option<DateTime<Local>>,
+    /// Aufnahmezeitpunkt der gerade verarbeiteten Daten (`--replay`)
+    pub capture_time: Option<DateTime<Local>>,
+    pub last_checkpoint: Option<Instant>,
+    /// Gesicherter Zustand, bis er gegen den ersten Zählerstand geprüft wurde
+    pub restored: Option<SavedState>,
+    pub registers: Registers,
+    /// Letzte Meldung je Register, siehe `REGISTER_EXPIRY` in sml.rs
+    pub registers_seen: HashMap<ObisCode, Instant>,
+    pub expected_obis: &'static [ObisCode],
+    pub profile_checked: bool,
+    pub stats: FrameStats,
+    pub last_stats_publish: Option<Instant>,
     //pub sin: f32,
 }
 
 impl SensorData {
     pub fn new() -> Self {
         Self {
-            wirkleistung: 0,
-            wirkleistung_alt: 0,
-            zaehlerstand: 0,
-            zaehlerstand_alt: 0,
-            zaehlerstand_diff: 0,
-            einspeisung: 0,
+            wirkleistung: 0.0,
+            wirkleistung_alt: 0.0,
+            zaehlerstand: 0.0,
+            zaehlerstand_alt: 0.0,
+            zaehlerstand_diff: 0.0,
+            einspeisung: 0.0,
+            einspeisung_vom_zaehler: false,
             einspeisung_sts: true,
+            feed_in_pending_since: None,
             last_integration_time: None,
-            last_mqtt_publish: None,
+            capture_time: None,
+            last_checkpoint: None,
+            restored: None,
+            registers: Registers::new(),
+            registers_seen: HashMap::new(),
+            expected_obis: &[],
+            profile_checked: false,
+            stats: FrameStats::default(),
+            last_stats_publish: None,
             // sin: 0.0,
         }
     }
@@ -1,20 +1,153 @@
-use rumqttc::{AsyncClient, MqttOptions, QoS};
-use std::time::Duration;
-use tracing::{info, error};
-use crate::config::Config;
-
-pub async fn init_mqtt(config: &Config) -> AsyncClient {
-    let mut mqttoptions = MqttOptions::new("sml1_rust", &config.mqtt_broker, config.mqtt_port);
-    mqttoptions.set_credentials(&config.mqtt_user, &config.mqtt_pass);
+use anyhow::Context;
+use async_trait::async_trait;
+use rumqttc::{
+    AsyncClient, Event, LastWill, MqttOptions, Packet, QoS, TlsConfiguration, Transport,
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::command::CommandHandler;
+use crate::config::{Config, MeterConfig, MqttTransport};
+use crate::discovery::Discovery;
+use crate::model::SharedAppState;
+use crate::queue::{MqttPublisher, OfflineQueue};
+use crate::sink::{Reading, Sink};
+use crate::stats::FrameStats;
+
+/// Topics eines Zählers. Zustände liegen unter
+/// `<base>/<component>/<node>/<object>/state`, Discovery-Configs unter
+/// `<discovery_prefix>/<component>/<node>/<object>/config`.
+#[derive(Debug, Clone)]
+pub struct Topics {
+    base: String,
+    discovery_prefix: String,
+    node: String,
+    status: String,
+}
+
+impl Topics {
+    pub fn new(base: &str, discovery_prefix: &str, node: &str, status: &str) -> Self {
+        Self {
+            base: base.trim_end_matches('/').to_string(),
+            discovery_prefix: discovery_prefix.trim_end_matches('/').to_string(),
+            node: node.to_string(),
+            status: status.to_string(),
+        }
+    }
+
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+
+    pub fn state(&self, component: &str, object: &str) -> String {
+        format!("{}/{}/{}/{}/state", self.base, component, self.node, object)
+    }
+
+    /// Verfügbarkeit des Zählers (`online`/`offline`, vom Watchdog gesetzt)
+    pub fn availability(&self) -> String {
+        format!("{}/sensor/{}/availability", self.base, self.node)
+    }
+
+    pub fn discovery(&self, component: &str, object: &str) -> String {
+        format!(
+            "{}/{}/{}/{}/config",
+            self.discovery_prefix, component, self.node, object
+        )
+    }
+}
+
+pub async fn init_mqtt(
+    config: &Config,
+    meters: &[MeterConfig],
+    app_state: SharedAppState,
+    commands: CommandHandler,
+    queue: Arc<OfflineQueue>,
+) -> anyhow::Result<AsyncClient> {
+    // Bei WebSocket ist die Adresse eine URL, der Port wird daraus gelesen
+    let broker_addr = match config.mqtt_transport {
+        MqttTransport::Tcp | MqttTransport::Tls => config.mqtt_broker.clone(),
+        MqttTransport::Ws => format!(
+            "ws://{}:{}{}",
+            config.mqtt_broker, config.mqtt_port, config.mqtt_ws_path
+        ),
+        MqttTransport::Wss => format!(
+            "wss://{}:{}{}",
+            config.mqtt_broker, config.mqtt_port, config.mqtt_ws_path
+        ),
+    };
+    // Status des Dienstes (`online`/`offline`, retained). `offline` setzt der Broker
+    // als Last Will, wenn die Verbindung ohne Abmeldung abreißt.
+    let status = config.mqtt_status_topic.clone();
+    let mut mqttoptions = MqttOptions::new(&config.mqtt_client_id, broker_addr, config.mqtt_port);
+    mqttoptions.set_transport(transport(config)?);
+    if let Some(user) = &config.mqtt_user {
+        mqttoptions.set_credentials(user, config.mqtt_pass.as_deref().unwrap_or_default());
+    }
     mqttoptions.set_keep_alive(Duration::from_secs(5));
-    
+    mqttoptions.set_last_will(LastWill::new(&status, "offline", QoS::AtLeastOnce, true));
+
     let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
 
+    // Birth-Message von Home Assistant, danach müssen die Discovery-Configs neu gesendet werden
+    let ha_status = format!(
+        "{}/status",
+        config.mqtt_discovery_prefix.trim_end_matches('/')
+    );
+    // Leeres Command-Topic: keine Befehle annehmen
+    let command_topic = Some(config.mqtt_command_topic.clone()).filter(|t| !t.is_empty());
+    let announcer = Announcer {
+        client: client.clone(),
+        status,
+        ha_status: ha_status.clone(),
+        command_topic: command_topic.clone(),
+        meters: meters.to_vec(),
+        app_state: app_state.clone(),
+    };
+    let broker = format!(
+        "{}:{} ({:?})",
+        config.mqtt_broker, config.mqtt_port, config.mqtt_transport
+    );
     tokio::spawn(async move {
         loop {
             match eventloop.poll().await {
-                Ok(_) => {},
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("MQTT connected to {}", broker);
+                    queue.set_connected(true);
+                    set_connected(&app_state, true);
+                    queue.start_drain(&announcer.client);
+                    // Nicht in dieser Schleife warten: die Publishes landen in der
+                    // Request-Queue, die nur von `poll` geleert wird.
+                    tokio::spawn(announcer.clone().announce());
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) if p.topic == ha_status => {
+                    if p.payload.as_ref() == b"online" {
+                        info!("Home Assistant restarted, re-sending discovery");
+                        tokio::spawn(announcer.clone().announce());
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(p)))
+                    if Some(&p.topic) == command_topic.as_ref() =>
+                {
+                    // Retained-Befehle würden bei jedem Verbinden erneut ausgeführt
+                    if p.retain {
+                        warn!("Ignoring retained MQTT command on {}", p.topic);
+                    } else {
+                        tokio::spawn(
+                            commands
+                                .clone()
+                                .handle(announcer.client.clone(), p.payload.to_vec()),
+                        );
+                    }
+                }
+                Ok(_) => {}
                 Err(e) => {
+                    queue.set_connected(false);
+                    set_connected(&app_state, false);
                     error!("MQTT Connection Error: {:?}", e);
                     tokio::time::sleep(Duration::from_secs(2)).await;
                 }
@@ -22,20 +155,211 @@ pub async fn init_mqtt(config: &Config) -> AsyncClient {
         }
     });
 
-    info!("MQTT connected to {}:{}", config.mqtt_broker, config.mqtt_port);
-    send_mqtt_config(&client).await;
-    client
+    Ok(client)
+}
+
+/// Verbindungszustand für `/metrics`
+fn set_connected(app_state: &SharedAppState, connected: bool) {
+    if let Ok(mut state) = app_state.lock() {
+        state.mqtt_connected = Some(connected);
+    }
 }
 
-async fn send_mqtt_config(client: &AsyncClient) {
-    let configs = vec![
-        ("homeassistant/sensor/sml/power/config", r#"{"unique_id": "sml.power","device_class": "power", "name": "Wirkleistung", "state_topic": "homeassistant/sensor/sml/wirkleistung/state","unit_of_measurement": "W"}"#),
-        ("homeassistant/sensor/sml/bezug/config", r#"{"unique_id": "sml.bezug","state_class": "total_increasing", "device_class": "energy", "name": "Netzbezug", "state_topic": "homeassistant/sensor/sml/zaehler/state","unit_of_measurement": "Wh", "value_template": "{{ value_json.bezug}}"}"#),
-        ("homeassistant/sensor/sml/einspeisung/config", r#"{"unique_id": "sml.einspeisung","state_class": "total_increasing", "device_class": "energy", "name": "Netzeinspeisung", "state_topic": "homeassistant/sensor/sml/zaehler/state","unit_of_measurement": "Wh", "value_template": "{{ value_json.einspeisung}}"}"#),
-        ("homeassistant/binary_sensor/sml/feed/config", r#"{"unique_id": "sml.feed", "device_class": "power", "name": "Einspeisung", "state_topic": "homeassistant/binary_sensor/sml/feed/state"}"#)
-    ];
+fn transport(config: &Config) -> anyhow::Result<Transport> {
+    match config.mqtt_transport {
+        MqttTransport::Tcp => Ok(Transport::Tcp),
+        MqttTransport::Tls => Ok(Transport::tls_with_config(tls_config(config)?)),
+        #[cfg(feature = "websocket")]
+        MqttTransport::Ws => Ok(Transport::Ws),
+        #[cfg(feature = "websocket")]
+        MqttTransport::Wss => Ok(Transport::wss_with_config(tls_config(config)?)),
+        #[cfg(not(feature = "websocket"))]
+        MqttTransport::Ws | MqttTransport::Wss => {
+            anyhow::bail!("MQTT over WebSocket needs a build with `--features websocket`")
+        }
+    }
+}
 
-    for (topic, payload) in configs {
-        let _ = client.publish(topic, QoS::AtLeastOnce, true, payload).await;
+/// CA aus `--mqtt-ca` (sonst Systemzertifikate) und optional Client-Zertifikat
+fn tls_config(config: &Config) -> anyhow::Result<TlsConfiguration> {
+    let read = |path: &std::path::Path| {
+        std::fs::read(path).with_context(|| format!("reading {}", path.display()))
+    };
+    let client_auth = match (&config.mqtt_client_cert, &config.mqtt_client_key) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        (None, None) => None,
+        _ => anyhow::bail!("--mqtt-client-cert and --mqtt-client-key must be given together"),
+    };
+    match &config.mqtt_ca {
+        Some(ca) => Ok(TlsConfiguration::Simple {
+            ca: read(ca)?,
+            alpn: None,
+            client_auth,
+        }),
+        None if client_auth.is_some() => {
+            anyhow::bail!("--mqtt-client-cert needs --mqtt-ca")
+        }
+        None => Ok(TlsConfiguration::default()),
     }
-}
\ No newline at end of file
+}
+
+#[derive(Clone)]
+struct Announcer {
+    client: AsyncClient,
+    status: String,
+    ha_status: String,
+    command_topic: Option<String>,
+    meters: Vec<MeterConfig>,
+    app_state: SharedAppState,
+}
+
+impl Announcer {
+    /// Nach jedem (Wieder-)Verbinden: Birth-Message, Discovery, Verfügbarkeit
+    /// je Zähler und Abos auf den HA-Status und das Command-Topic
+    async fn announce(self) {
+        if let Err(e) = self
+            .client
+            .publish(&self.status, QoS::AtLeastOnce, true, "online")
+            .await
+        {
+            warn!("MQTT birth message failed: {:?}", e);
+        }
+        for meter in &self.meters {
+            // Entitäten aus den zuletzt empfangenen Registern, falls schon vorhanden
+            let (registers, available) = self
+                .app_state
+                .lock()
+                .ok()
+                .and_then(|s| {
+                    s.meters
+                        .iter()
+                        .find(|m| m.name == meter.name)
+                        .map(|m| (m.registers.clone(), m.available))
+                })
+                .unwrap_or_default();
+            let discovery = Discovery::new(meter, &registers);
+            discovery
+                .publish(&self.client, meter, &registers, None)
+                .await;
+            // Der Watchdog sendet nur Änderungen; ein Broker ohne Persistenz hat
+            // den Stand nach einem Neustart vergessen (`availability_mode: all`)
+            let _ = self
+                .client
+                .publish(
+                    meter.topics.availability(),
+                    QoS::AtLeastOnce,
+                    true,
+                    if available { "online" } else { "offline" },
+                )
+                .await;
+        }
+        let _ = self
+            .client
+            .subscribe(&self.ha_status, QoS::AtLeastOnce)
+            .await;
+        if let Some(topic) = &self.command_topic {
+            let _ = self.client.subscribe(topic, QoS::AtLeastOnce).await;
+        }
+    }
+}
+
+/// Beim regulären Beenden: der Last Will greift dabei nicht
+pub async fn publish_offline(client: &AsyncClient, config: &Config) {
+    let _ = client
+        .publish(&config.mqtt_status_topic, QoS::AtLeastOnce, true, "offline")
+        .await;
+}
+
+/// Zustände eines Zählers per MQTT: Zählerstände (im Abstand von
+/// `--mqtt-publish-interval`, bei getrennter Verbindung gepuffert), Einspeisung,
+/// Momentanwerte, Register und Discovery.
+pub struct MqttSink {
+    mqtt: MqttPublisher,
+    last_publish: Option<Instant>,
+    /// Zuletzt per Discovery angekündigtes Gerät und Register
+    discovery: Option<Discovery>,
+}
+
+impl MqttSink {
+    pub fn new(mqtt: MqttPublisher) -> Self {
+        Self {
+            mqtt,
+            last_publish: None,
+            discovery: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    async fn reading(&mut self, reading: &Reading<'_>) {
+        let meter = reading.meter;
+        if reading.feed_in_changed {
+            self.mqtt.publish(
+                meter.topics.state("binary_sensor", "feed"),
+                true,
+                if reading.feed_in { "ON" } else { "OFF" }.to_string(),
+            );
+        }
+
+        let now = Instant::now();
+        let should_publish = match self.last_publish {
+            Some(t) => now.duration_since(t) > meter.mqtt_publish_interval,
+            None => true,
+        };
+        if should_publish {
+            let json_payload = format!(
+                "{{\"Time\":\"{}\",\"bezug\":{:.1},\"einspeisung\":{:.1}}}",
+                reading.time.to_rfc3339(),
+                reading.bezug,
+                reading.einspeisung
+            );
+            // Bei getrennter Verbindung gepuffert, `Time` bleibt der Ablesezeitpunkt
+            self.mqtt
+                .publish(meter.topics.state("sensor", "zaehler"), false, json_payload);
+            self.last_publish = Some(now);
+        }
+
+        self.mqtt.publish_live(
+            meter.topics.state("sensor", "wirkleistung"),
+            format!("{:.1}", reading.wirkleistung),
+        );
+
+        // Alle Register des Zählers (Tarife, Einspeisung, Leistung je Phase, ...)
+        match serde_json::to_string(reading.registers) {
+            Ok(payload) => self
+                .mqtt
+                .publish_live(meter.topics.state("sensor", "register"), payload),
+            Err(e) => error!("Error serializing registers: {}", e),
+        }
+
+        // Gerät und Register sind erst mit den ersten Daten bekannt; neue Register
+        // ankündigen, verschwundene entfernen. Ohne Verbindung übernimmt das die
+        // Discovery nach dem Verbinden.
+        let discovery = Discovery::new(meter, reading.registers);
+        if self.mqtt.is_connected() && self.discovery.as_ref() != Some(&discovery) {
+            discovery
+                .publish(
+                    self.mqtt.client(),
+                    meter,
+                    reading.registers,
+                    self.discovery.as_ref(),
+                )
+                .await;
+            self.discovery = Some(discovery);
+        }
+    }
+
+    async fn stats(&mut self, meter: &MeterConfig, stats: &FrameStats) {
+        match serde_json::to_string(stats) {
+            Ok(payload) => self
+                .mqtt
+                .publish_live(meter.topics.state("sensor", "stats"), payload),
+            Err(e) => error!("Error serializing frame statistics: {}", e),
+        }
+    }
+}
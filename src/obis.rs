@@ -0,0 +1,313 @@
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// OBIS-Kennzahl A-B:C.D.E*F
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct ObisCode(pub [u8; 6]);
+
+impl ObisCode {
+    pub const fn new(a: u8, b: u8, c: u8, d: u8, e: u8, f: u8) -> Self {
+        ObisCode([a, b, c, d, e, f])
+    }
+
+    pub fn from_slice(s: &[u8]) -> Option<Self> {
+        let mut code = [0u8; 6];
+        if s.len() != code.len() {
+            return None;
+        }
+        code.copy_from_slice(s);
+        Some(ObisCode(code))
+    }
+
+    /// Klartextname aus der Tabelle bekannter Kennzahlen
+    pub fn name(&self) -> Option<&'static str> {
+        NAMES.iter().find(|(c, _)| c == self).map(|(_, n)| *n)
+    }
+}
+
+impl fmt::Display for ObisCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, _] = self.0;
+        write!(f, "{}-{}:{}.{}.{}", a, b, c, d, e)
+    }
+}
+
+impl Serialize for ObisCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+pub const ZAEHLERNUMMER: ObisCode = ObisCode::new(1, 0, 0, 0, 0, 255);
+pub const SERIENNUMMER: ObisCode = ObisCode::new(1, 0, 0, 0, 1, 255);
+pub const GERAETE_ID: ObisCode = ObisCode::new(1, 0, 0, 0, 9, 255);
+pub const ZAEHLERSTAND: ObisCode = ObisCode::new(1, 0, 1, 8, 0, 255);
+pub const BEZUG_TARIF1: ObisCode = ObisCode::new(1, 0, 1, 8, 1, 255);
+pub const BEZUG_TARIF2: ObisCode = ObisCode::new(1, 0, 1, 8, 2, 255);
+pub const EINSPEISUNG: ObisCode = ObisCode::new(1, 0, 2, 8, 0, 255);
+pub const WIRKLEISTUNG: ObisCode = ObisCode::new(1, 0, 16, 7, 0, 255);
+pub const LEISTUNG_L1: ObisCode = ObisCode::new(1, 0, 36, 7, 0, 255);
+pub const LEISTUNG_L2: ObisCode = ObisCode::new(1, 0, 56, 7, 0, 255);
+pub const LEISTUNG_L3: ObisCode = ObisCode::new(1, 0, 76, 7, 0, 255);
+/// EasyMeter Q3A/Q3D (D0) melden die Leistung als 1.7.255 bzw. 21/41/61.7.255
+pub const WIRKLEISTUNG_D0: ObisCode = ObisCode::new(1, 0, 1, 7, 255, 255);
+pub const LEISTUNG_L1_D0: ObisCode = ObisCode::new(1, 0, 21, 7, 255, 255);
+pub const LEISTUNG_L2_D0: ObisCode = ObisCode::new(1, 0, 41, 7, 255, 255);
+pub const LEISTUNG_L3_D0: ObisCode = ObisCode::new(1, 0, 61, 7, 255, 255);
+pub const HERSTELLER: ObisCode = ObisCode::new(129, 129, 199, 130, 3, 255);
+pub const PUBLIC_KEY: ObisCode = ObisCode::new(129, 129, 199, 130, 5, 255);
+pub const GERAETENUMMER: ObisCode = ObisCode::new(0, 0, 96, 1, 0, 255);
+/// D0-Zähler (z.B. EasyMeter) melden die Gerätenummer als 0-0:96.1.255
+pub const GERAETENUMMER_D0: ObisCode = ObisCode::new(0, 0, 96, 1, 255, 255);
+
+const NAMES: &[(ObisCode, &str)] = &[
+    (ZAEHLERNUMMER, "Eigentumsnummer"),
+    (SERIENNUMMER, "Seriennummer"),
+    (GERAETE_ID, "Geräteeinzelidentifikation"),
+    (ZAEHLERSTAND, "Zählerstand Bezug"),
+    (BEZUG_TARIF1, "Bezug Tarif 1"),
+    (BEZUG_TARIF2, "Bezug Tarif 2"),
+    (EINSPEISUNG, "Zählerstand Einspeisung"),
+    (WIRKLEISTUNG, "Leistung"),
+    (LEISTUNG_L1, "Leistung an L1"),
+    (LEISTUNG_L2, "Leistung an L2"),
+    (LEISTUNG_L3, "Leistung an L3"),
+    (WIRKLEISTUNG_D0, "Leistung"),
+    (LEISTUNG_L1_D0, "Leistung an L1"),
+    (LEISTUNG_L2_D0, "Leistung an L2"),
+    (LEISTUNG_L3_D0, "Leistung an L3"),
+    (HERSTELLER, "Herstelleridentifikation"),
+    (PUBLIC_KEY, "Public Key"),
+    (GERAETENUMMER, "Gerätenummer"),
+    (GERAETENUMMER_D0, "Gerätenummer"),
+];
+
+/// Rohwert eines Registers
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegisterValue {
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+impl fmt::Display for RegisterValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterValue::Int(i) => write!(f, "{}", i),
+            RegisterValue::Bytes(b) => b.iter().try_for_each(|b| write!(f, "{:02x}", b)),
+        }
+    }
+}
+
+impl Serialize for RegisterValue {
+    /// Zahlen als JSON-Zahl, Bytefolgen als Hex-String
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            RegisterValue::Int(i) => serializer.serialize_i64(*i),
+            RegisterValue::Bytes(_) => serializer.collect_str(self),
+        }
+    }
+}
+
+/// Ein Register des Zählers mit Einheit (DLMS-Code), Skalierer und Status,
+/// so wie es in einem `GetListResponse` (bzw. D0-Telegramm) übertragen wird.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Register {
+    pub value: RegisterValue,
+    pub scaler: Option<i8>,
+    pub unit: Option<u8>,
+    pub status: Option<u64>,
+}
+
+impl Register {
+    pub fn as_int(&self) -> Option<i64> {
+        match self.value {
+            RegisterValue::Int(i) => Some(i),
+            RegisterValue::Bytes(_) => None,
+        }
+    }
+
+    /// Wert als Text: druckbare Bytefolgen (z.B. Herstellerkennung) direkt,
+    /// sonst hexadezimal
+    pub fn as_text(&self) -> Option<String> {
+        let text = match &self.value {
+            RegisterValue::Bytes(b) if b.is_empty() => return None,
+            RegisterValue::Bytes(b) if b.iter().all(|c| c.is_ascii_graphic() || *c == b' ') => {
+                String::from_utf8_lossy(b).trim().to_string()
+            }
+            value => value.to_string(),
+        };
+        Some(text).filter(|t| !t.is_empty())
+    }
+
+    /// Physikalischer Wert: Rohwert * 10^Skalierer
+    pub fn scaled(&self) -> Option<f64> {
+        self.as_int()
+            .map(|v| v as f64 * 10f64.powi(self.scaler.unwrap_or(0) as i32))
+    }
+
+    pub fn unit_name(&self) -> Option<&'static str> {
+        self.unit.and_then(unit_name)
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scaled() {
+            Some(v) => write!(f, "{}", v)?,
+            None => write!(f, "{}", self.value)?,
+        }
+        match self.unit_name() {
+            Some(unit) => write!(f, " {}", unit),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Serialize for Register {
+    /// `value` ist der skalierte Wert in `unit`, `raw` und `scaler` der Rohwert
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Register", 5)?;
+        match self.scaled() {
+            Some(v) => s.serialize_field("value", &v)?,
+            None => s.serialize_field("value", &self.value)?,
+        }
+        s.serialize_field("unit", &self.unit_name())?;
+        s.serialize_field("raw", &self.value)?;
+        s.serialize_field("scaler", &self.scaler)?;
+        s.serialize_field("status", &self.status)?;
+        s.end()
+    }
+}
+
+/// DLMS-Einheitencode (IEC 62056-62) → Kurzzeichen
+pub fn unit_name(code: u8) -> Option<&'static str> {
+    let name = match code {
+        1 => "a",
+        2 => "mo",
+        3 => "wk",
+        4 => "d",
+        5 => "h",
+        6 => "min",
+        7 => "s",
+        8 => "°",
+        9 => "°C",
+        13 => "m³",
+        14 => "m³",
+        27 => "W",
+        28 => "VA",
+        29 => "var",
+        30 => "Wh",
+        31 => "VAh",
+        32 => "varh",
+        33 => "A",
+        34 => "C",
+        35 => "V",
+        44 => "Hz",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Alle zuletzt empfangenen Register, nach OBIS-Kennzahl sortiert
+pub type Registers = BTreeMap<ObisCode, Register>;
+
+/// Wirkleistung in W aus 16.7.0 (SML), sonst aus 1.7.255 (D0)
+pub fn wirkleistung(registers: &Registers) -> Option<f64> {
+    [WIRKLEISTUNG, WIRKLEISTUNG_D0]
+        .iter()
+        .find_map(|code| registers.get(code))
+        .and_then(Register::scaled)
+}
+
+/// Leistung je Phase L1–L3 aus 36/56/76.7.0 (SML), sonst aus 21/41/61.7.255 (D0)
+pub fn leistung_phasen(registers: &Registers) -> [Option<f64>; 3] {
+    [
+        [LEISTUNG_L1, LEISTUNG_L1_D0],
+        [LEISTUNG_L2, LEISTUNG_L2_D0],
+        [LEISTUNG_L3, LEISTUNG_L3_D0],
+    ]
+    .map(|codes| {
+        codes
+            .iter()
+            .find_map(|code| registers.get(code))
+            .and_then(Register::scaled)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(value: RegisterValue, scaler: Option<i8>, unit: Option<u8>) -> Register {
+        Register {
+            value,
+            scaler,
+            unit,
+            status: None,
+        }
+    }
+
+    #[test]
+    fn scaled_applies_scaler() {
+        // 1.8.0 eines eHZ: 0,1 Wh Auflösung
+        let bezug = register(RegisterValue::Int(123456789), Some(-1), Some(30));
+        assert!((bezug.scaled().unwrap() - 12345678.9).abs() < 1e-6);
+        // Leistung mit Skalierer 0 und negativem Wert (Einspeisung)
+        let leistung = register(RegisterValue::Int(-345), Some(0), Some(27));
+        assert_eq!(leistung.scaled(), Some(-345.0));
+        // kWh-Zähler mit positivem Skalierer
+        let kwh = register(RegisterValue::Int(5273), Some(3), Some(30));
+        assert_eq!(kwh.scaled(), Some(5273000.0));
+    }
+
+    #[test]
+    fn scaled_without_scaler_or_number() {
+        let raw = register(RegisterValue::Int(82), None, None);
+        assert_eq!(raw.scaled(), Some(82.0));
+        let hersteller = register(RegisterValue::Bytes(b"EMH".to_vec()), Some(-1), None);
+        assert_eq!(hersteller.scaled(), None);
+    }
+
+    #[test]
+    fn display_with_unit() {
+        let leistung = register(RegisterValue::Int(-3450), Some(-1), Some(27));
+        assert_eq!(leistung.to_string(), "-345 W");
+        let hersteller = register(RegisterValue::Bytes(b"EMH".to_vec()), None, None);
+        assert_eq!(hersteller.to_string(), "454d48");
+    }
+
+    #[test]
+    fn obis_code_from_slice() {
+        assert_eq!(
+            ObisCode::from_slice(&[1, 0, 1, 8, 0, 255]),
+            Some(ZAEHLERSTAND)
+        );
+        assert_eq!(ObisCode::from_slice(&[1, 0, 1, 8, 0]), None);
+        assert_eq!(ZAEHLERSTAND.to_string(), "1-0:1.8.0");
+        assert_eq!(ZAEHLERSTAND.name(), Some("Zählerstand Bezug"));
+    }
+
+    #[test]
+    fn wirkleistung_falls_back_to_d0_codes() {
+        let mut registers = Registers::new();
+        registers.insert(
+            WIRKLEISTUNG_D0,
+            register(RegisterValue::Int(136), Some(0), Some(27)),
+        );
+        registers.insert(
+            LEISTUNG_L2_D0,
+            register(RegisterValue::Int(21), Some(0), Some(27)),
+        );
+        assert_eq!(wirkleistung(&registers), Some(136.0));
+        assert_eq!(leistung_phasen(&registers), [None, Some(21.0), None]);
+
+        // 16.7.0 hat Vorrang
+        registers.insert(
+            WIRKLEISTUNG,
+            register(RegisterValue::Int(-345), Some(0), Some(27)),
+        );
+        assert_eq!(wirkleistung(&registers), Some(-345.0));
+    }
+}
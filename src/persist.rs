@@ -0,0 +1,104 @@
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::{error, info, warn};
+
+use crate::model::SensorData;
+
+/// Zählerstände, die über einen Neustart erhalten bleiben müssen
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedState {
+    pub saved_at: String,
+    /// Zählerstand Bezug zum Zeitpunkt der Sicherung (Wh), für die Plausibilitätsprüfung
+    pub zaehlerstand: f64,
+    /// Integrierte bzw. vom Zähler gelesene Einspeisung (Wh)
+    pub einspeisung: f64,
+}
+
+pub fn load(path: &Path) -> Option<SavedState> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            error!("Error reading state file {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    match serde_json::from_str::<SavedState>(&data) {
+        Ok(state) if state.zaehlerstand.is_finite() && state.einspeisung.is_finite() => {
+            info!(
+                "Restored state from {} (saved {}): Einspeisung {:.1} Wh",
+                path.display(),
+                state.saved_at,
+                state.einspeisung
+            );
+            Some(state)
+        }
+        Ok(_) => {
+            warn!("Ignoring state file {} with invalid values", path.display());
+            None
+        }
+        Err(e) => {
+            error!("Error parsing state file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Schreibt den Zustand atomar (temporäre Datei + rename), damit ein Absturz
+/// während des Schreibens keine halbe Datei hinterlässt.
+pub fn save(path: &Path, sensor: &SensorData) {
+    // Ohne gültigen Zählerstand gibt es nichts Sinnvolles zu sichern
+    if sensor.zaehlerstand <= 0.0 {
+        return;
+    }
+    let state = SavedState {
+        saved_at: Local::now().to_rfc3339(),
+        zaehlerstand: sensor.zaehlerstand,
+        einspeisung: sensor.einspeisung,
+    };
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            if let Err(e) = std::fs::create_dir_all(parent) {
+                error!(
+                    "Failed to create state directory {}: {}",
+                    parent.display(),
+                    e
+                );
+                return;
+            }
+        }
+    }
+    let tmp = path.with_extension("tmp");
+    let result = serde_json::to_vec_pretty(&state)
+        .map_err(std::io::Error::other)
+        .and_then(|data| std::fs::write(&tmp, data))
+        .and_then(|_| std::fs::rename(&tmp, path));
+    if let Err(e) = result {
+        error!("Error writing state file {}: {}", path.display(), e);
+    }
+}
+
+/// Prüft den gesicherten Zustand gegen den ersten Zählerstand nach dem Start.
+/// Ist der Zähler kleiner als zum Sicherungszeitpunkt, wurde er getauscht oder
+/// zurückgesetzt, dann wird die gesicherte Einspeisung verworfen.
+pub fn validate_restored(sensor: &mut SensorData, zaehlerstand: f64) {
+    let saved = match sensor.restored.take() {
+        Some(s) => s,
+        None => return,
+    };
+    if zaehlerstand + 0.5 < saved.zaehlerstand {
+        warn!(
+            "Meter reading {:.1} Wh is below saved reading {:.1} Wh, discarding saved state",
+            zaehlerstand, saved.zaehlerstand
+        );
+        if !sensor.einspeisung_vom_zaehler {
+            sensor.einspeisung = 0.0;
+        }
+    } else {
+        info!(
+            "Saved state plausible: meter {:.1} Wh, saved {:.1} Wh",
+            zaehlerstand, saved.zaehlerstand
+        );
+    }
+}
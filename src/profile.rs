@@ -0,0 +1,194 @@
+use serialport::{DataBits, FlowControl, Parity, StopBits};
+
+use crate::obis::{self, ObisCode};
+
+/// Einstellungen der seriellen Schnittstelle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl Default for SerialSettings {
+    /// 9600 Baud 8N1, wie bei den meisten SML-Zählern
+    fn default() -> Self {
+        Self {
+            baud_rate: 9600,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+/// Protokoll des Zählers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Binäres SML (Smart Message Language)
+    Sml,
+    /// IEC 62056-21 Klartext. Mit `mode_c` wird der Zähler aktiv abgefragt
+    /// (Anfrage mit 300 Baud, danach Umschaltung auf die angebotene Baudrate).
+    D0 { mode_c: bool },
+}
+
+impl Protocol {
+    /// Schnittstellenparameter ohne Zählerprofil: D0 nutzt immer 7E1, im
+    /// Modus C beginnt die Abfrage mit 300 Baud
+    pub fn default_serial(self) -> SerialSettings {
+        match self {
+            Protocol::Sml => SML_8N1,
+            Protocol::D0 { mode_c: false } => D0_7E1,
+            Protocol::D0 { mode_c: true } => D0_MODE_C,
+        }
+    }
+}
+
+/// Benanntes Zählerprofil: Schnittstellenparameter und erwartete OBIS-Kennzahlen
+#[derive(Debug)]
+pub struct MeterProfile {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub protocol: Protocol,
+    pub serial: SerialSettings,
+    pub obis: &'static [ObisCode],
+}
+
+const SML_8N1: SerialSettings = SerialSettings {
+    baud_rate: 9600,
+    data_bits: DataBits::Eight,
+    parity: Parity::None,
+    stop_bits: StopBits::One,
+    flow_control: FlowControl::None,
+};
+
+const D0_MODE_C: SerialSettings = SerialSettings {
+    baud_rate: 300,
+    data_bits: DataBits::Seven,
+    parity: Parity::Even,
+    stop_bits: StopBits::One,
+    flow_control: FlowControl::None,
+};
+
+const D0_7E1: SerialSettings = SerialSettings {
+    baud_rate: 9600,
+    data_bits: DataBits::Seven,
+    parity: Parity::Even,
+    stop_bits: StopBits::One,
+    flow_control: FlowControl::None,
+};
+
+pub const PROFILES: &[MeterProfile] = &[
+    MeterProfile {
+        name: "generic-sml",
+        description: "SML-Zähler mit 9600 8N1 (Bezug, Wirkleistung)",
+        protocol: Protocol::Sml,
+        serial: SML_8N1,
+        obis: &[obis::ZAEHLERSTAND, obis::WIRKLEISTUNG],
+    },
+    MeterProfile {
+        name: "ehz-edl21",
+        description: "EMH eHZ / EDL21 mit Zweitarif",
+        protocol: Protocol::Sml,
+        serial: SML_8N1,
+        obis: &[
+            obis::ZAEHLERSTAND,
+            obis::BEZUG_TARIF1,
+            obis::BEZUG_TARIF2,
+            obis::WIRKLEISTUNG,
+        ],
+    },
+    MeterProfile {
+        name: "iskra-mt631",
+        description: "ISKRA MT631 Zweirichtungszähler",
+        protocol: Protocol::Sml,
+        serial: SML_8N1,
+        obis: &[obis::ZAEHLERSTAND, obis::EINSPEISUNG, obis::WIRKLEISTUNG],
+    },
+    MeterProfile {
+        name: "easymeter-q3a",
+        description: "EasyMeter Q3A, 9600 7E1",
+        protocol: Protocol::D0 { mode_c: false },
+        serial: D0_7E1,
+        obis: &[obis::ZAEHLERSTAND, obis::EINSPEISUNG, obis::WIRKLEISTUNG_D0],
+    },
+    MeterProfile {
+        name: "easymeter-q3d",
+        description: "EasyMeter Q3D mit Leistung je Phase, 9600 7E1",
+        protocol: Protocol::D0 { mode_c: false },
+        serial: D0_7E1,
+        obis: &[
+            obis::ZAEHLERSTAND,
+            obis::WIRKLEISTUNG_D0,
+            obis::LEISTUNG_L1_D0,
+            obis::LEISTUNG_L2_D0,
+            obis::LEISTUNG_L3_D0,
+        ],
+    },
+    MeterProfile {
+        name: "iec-62056-21",
+        description: "D0-Zähler im Modus C (Abfrage mit 300 7E1)",
+        protocol: Protocol::D0 { mode_c: true },
+        serial: D0_MODE_C,
+        obis: &[obis::ZAEHLERSTAND],
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static MeterProfile> {
+    PROFILES.iter().find(|p| p.name == name)
+}
+
+pub fn parse_profile(s: &str) -> Result<&'static MeterProfile, String> {
+    find(s).ok_or_else(|| {
+        let names: Vec<&str> = PROFILES.iter().map(|p| p.name).collect();
+        format!("unknown meter profile {} (known: {})", s, names.join(", "))
+    })
+}
+
+pub fn parse_protocol(s: &str) -> Result<Protocol, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "sml" => Ok(Protocol::Sml),
+        "d0" => Ok(Protocol::D0 { mode_c: false }),
+        "d0-mode-c" => Ok(Protocol::D0 { mode_c: true }),
+        _ => Err(format!("protocol must be sml, d0 or d0-mode-c: {}", s)),
+    }
+}
+
+pub fn parse_data_bits(s: &str) -> Result<DataBits, String> {
+    s.parse::<u8>()
+        .ok()
+        .and_then(|n| DataBits::try_from(n).ok())
+        .ok_or_else(|| format!("data bits must be 5, 6, 7 or 8: {}", s))
+}
+
+pub fn parse_parity(s: &str) -> Result<Parity, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "none" | "n" => Ok(Parity::None),
+        "even" | "e" => Ok(Parity::Even),
+        "odd" | "o" => Ok(Parity::Odd),
+        _ => Err(format!("parity must be none, even or odd: {}", s)),
+    }
+}
+
+pub fn parse_stop_bits(s: &str) -> Result<StopBits, String> {
+    match s {
+        "1" => Ok(StopBits::One),
+        "2" => Ok(StopBits::Two),
+        _ => Err(format!("stop bits must be 1 or 2: {}", s)),
+    }
+}
+
+pub fn parse_flow_control(s: &str) -> Result<FlowControl, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "none" => Ok(FlowControl::None),
+        "software" | "xonxoff" => Ok(FlowControl::Software),
+        "hardware" | "rtscts" => Ok(FlowControl::Hardware),
+        _ => Err(format!(
+            "flow control must be none, software or hardware: {}",
+            s
+        )),
+    }
+}
@@ -0,0 +1,381 @@
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+/// Wartezeit je Nachricht beim Nachsenden, danach gilt die Verbindung als gestört
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Eine gepufferte Nachricht, eine JSON-Zeile in der Queue-Datei
+#[derive(Serialize, Deserialize)]
+struct QueuedMessage {
+    topic: String,
+    retain: bool,
+    payload: String,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// Nachrichten in der Datei
+    len: usize,
+    /// Wegen Überlauf vorne verworfene Nachrichten seit dem Start; `drain`
+    /// gleicht damit ab, welche der gesendeten schon aus der Datei sind
+    dropped: u64,
+    draining: bool,
+}
+
+/// Puffer für Zählerstände, solange der Broker nicht erreichbar ist. Die
+/// Nachrichten werden in einer Datei (JSON Lines) gesammelt und nach dem
+/// Wiederverbinden in derselben Reihenfolge gesendet; der Zeitstempel steht im
+/// Payload. Ist die Datei voll, fallen die ältesten Nachrichten heraus.
+pub struct OfflineQueue {
+    path: PathBuf,
+    max_entries: usize,
+    connected: AtomicBool,
+    state: Mutex<QueueState>,
+}
+
+impl OfflineQueue {
+    /// Übernimmt noch nicht gesendete Nachrichten aus einem früheren Lauf
+    pub fn open(path: PathBuf, max_entries: usize) -> Arc<Self> {
+        let len = fs::read_to_string(&path)
+            .map(|s| s.lines().count())
+            .unwrap_or(0);
+        if len > 0 {
+            info!("{} buffered MQTT messages in {}", len, path.display());
+        }
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        Arc::new(Self {
+            path,
+            max_entries,
+            connected: AtomicBool::new(false),
+            state: Mutex::new(QueueState {
+                len,
+                dropped: 0,
+                draining: false,
+            }),
+        })
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Momentanwerte ohne Pufferung: bei getrennter Verbindung verwerfen statt zu warten
+    pub fn publish_live(&self, client: &AsyncClient, topic: String, payload: String) {
+        if !self.is_connected() {
+            return;
+        }
+        if let Err(e) = client.try_publish(&topic, QoS::AtLeastOnce, false, payload) {
+            debug!("MQTT publish to {} dropped: {}", topic, e);
+        }
+    }
+
+    /// Sendet sofort, wenn verbunden und nichts gepuffert ist, sonst wird die
+    /// Nachricht hinten angehängt. Blockiert nie die Eingabeschleife.
+    pub fn publish(
+        self: &Arc<Self>,
+        client: &AsyncClient,
+        topic: String,
+        retain: bool,
+        payload: String,
+    ) {
+        let connected = self.is_connected();
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        if connected && state.len == 0 && !state.draining {
+            match client.try_publish(&topic, QoS::AtLeastOnce, retain, payload.clone()) {
+                Ok(()) => return,
+                // Request-Queue voll: über die Datei, damit die Reihenfolge erhalten bleibt
+                Err(e) => warn!("MQTT publish to {} deferred: {}", topic, e),
+            }
+        }
+        if self.max_entries == 0 {
+            return;
+        }
+        if let Err(e) = self.append(
+            &mut state,
+            &QueuedMessage {
+                topic,
+                retain,
+                payload,
+            },
+        ) {
+            error!("Error writing MQTT queue {}: {}", self.path.display(), e);
+            return;
+        }
+        if connected && !state.draining {
+            state.draining = true;
+            tokio::spawn(self.clone().drain(client.clone()));
+        }
+    }
+
+    /// Nach dem (Wieder-)Verbinden: gepufferte Nachrichten nachsenden
+    pub fn start_drain(self: &Arc<Self>, client: &AsyncClient) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        if state.len > 0 && !state.draining {
+            state.draining = true;
+            tokio::spawn(self.clone().drain(client.clone()));
+        }
+    }
+
+    /// "Gesendet" heißt hier: an die Request-Queue von rumqttc übergeben, nicht
+    /// vom Broker bestätigt. Reißt die Verbindung davor ab, wiederholt rumqttc
+    /// QoS-1-Nachrichten nur, solange der Prozess läuft.
+    async fn drain(self: Arc<Self>, client: AsyncClient) {
+        let mut total = 0;
+        loop {
+            // Während des Sendens kommen neue Nachrichten hinten dazu, deshalb
+            // werden nach jedem Durchgang nur die gesendeten vorne entfernt.
+            let (messages, dropped_before) = match self.state.lock() {
+                Ok(state) => (self.read(), state.dropped),
+                Err(_) => return,
+            };
+            let mut sent: usize = 0;
+            for message in &messages {
+                // Unlesbare Zeilen überspringen, sie zählen als gesendet
+                let Some(message) = message else {
+                    sent += 1;
+                    continue;
+                };
+                let publish = client.publish(
+                    &message.topic,
+                    QoS::AtLeastOnce,
+                    message.retain,
+                    message.payload.clone(),
+                );
+                match tokio::time::timeout(DRAIN_TIMEOUT, publish).await {
+                    Ok(Ok(())) => sent += 1,
+                    _ => break,
+                }
+            }
+            total += sent;
+
+            let Ok(mut state) = self.state.lock() else {
+                return;
+            };
+            // Ist die Datei zwischendurch übergelaufen, sind die ältesten (also
+            // zuerst gesendeten) Nachrichten schon weg
+            let dropped = usize::try_from(state.dropped - dropped_before).unwrap_or(usize::MAX);
+            if let Err(e) = self.remove_front(&mut state, sent.saturating_sub(dropped)) {
+                error!("Error writing MQTT queue {}: {}", self.path.display(), e);
+                state.draining = false;
+                return;
+            }
+            if sent < messages.len() || state.len == 0 {
+                state.draining = false;
+                if state.len == 0 {
+                    info!("{} buffered MQTT messages sent", total);
+                } else {
+                    warn!(
+                        "MQTT connection lost while sending buffered messages, {} left",
+                        state.len
+                    );
+                }
+                return;
+            }
+        }
+    }
+
+    fn read(&self) -> Vec<Option<QueuedMessage>> {
+        fs::read_to_string(&self.path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).ok())
+            .collect()
+    }
+
+    fn append(&self, state: &mut QueueState, message: &QueuedMessage) -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(message)?)?;
+        state.len += 1;
+        if state.len > self.max_entries {
+            // Nicht bei jeder Nachricht neu schreiben: ein Zehntel Luft lassen
+            let drop = state.len - self.max_entries * 9 / 10;
+            warn!("MQTT queue full, dropping {} oldest messages", drop);
+            self.remove_front(state, drop)?;
+            state.dropped += drop as u64;
+        }
+        Ok(())
+    }
+
+    fn remove_front(&self, state: &mut QueueState, count: usize) -> std::io::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let content = fs::read_to_string(&self.path).unwrap_or_default();
+        let rest: Vec<&str> = content.lines().skip(count).collect();
+        if rest.is_empty() {
+            fs::write(&self.path, "")?;
+        } else {
+            fs::write(&self.path, rest.join("\n") + "\n")?;
+        }
+        state.len = rest.len();
+        Ok(())
+    }
+}
+
+/// MQTT-Client mit Offline-Puffer, so wie ihn die Eingabeschleifen benutzen
+#[derive(Clone)]
+pub struct MqttPublisher {
+    client: AsyncClient,
+    queue: Arc<OfflineQueue>,
+}
+
+impl MqttPublisher {
+    pub fn new(client: AsyncClient, queue: Arc<OfflineQueue>) -> Self {
+        Self { client, queue }
+    }
+
+    pub fn client(&self) -> &AsyncClient {
+        &self.client
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.queue.is_connected()
+    }
+
+    /// Zustände (Zählerstand, Einspeisung): bei getrennter Verbindung gepuffert
+    pub fn publish(&self, topic: String, retain: bool, payload: String) {
+        self.queue.publish(&self.client, topic, retain, payload);
+    }
+
+    /// Momentanwerte: bei getrennter Verbindung verworfen
+    pub fn publish_live(&self, topic: String, payload: String) {
+        self.queue.publish_live(&self.client, topic, payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::{MqttOptions, Request};
+
+    /// Eigene Datei je Test, Tests laufen parallel
+    fn queue_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "sml_rust_queue_{}_{}.jsonl",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Client ohne Broker: die Eventloop wird nie abgefragt, gesendete
+    /// Nachrichten bleiben in ihrem Kanal
+    fn client() -> (AsyncClient, rumqttc::EventLoop) {
+        AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 16)
+    }
+
+    fn topics(queue: &OfflineQueue) -> Vec<String> {
+        queue.read().into_iter().map(|m| m.unwrap().topic).collect()
+    }
+
+    fn publish(queue: &Arc<OfflineQueue>, client: &AsyncClient, range: std::ops::Range<usize>) {
+        for i in range {
+            queue.publish(client, format!("m{}", i), true, format!("{{\"n\":{}}}", i));
+        }
+    }
+
+    #[tokio::test]
+    async fn buffered_in_order_and_restored() {
+        let path = queue_path("restore");
+        let (client, _eventloop) = client();
+        let queue = OfflineQueue::open(path.clone(), 100);
+        publish(&queue, &client, 0..3);
+        assert_eq!(topics(&queue), ["m0", "m1", "m2"]);
+        let message = queue.read().remove(0).unwrap();
+        assert!(message.retain);
+        assert_eq!(message.payload, "{\"n\":0}");
+        drop(queue);
+
+        // Neustart: die Nachrichten aus der Datei werden übernommen
+        let queue = OfflineQueue::open(path.clone(), 100);
+        assert_eq!(queue.state.lock().unwrap().len, 3);
+        publish(&queue, &client, 3..4);
+        assert_eq!(topics(&queue), ["m0", "m1", "m2", "m3"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn overflow_drops_oldest() {
+        let path = queue_path("overflow");
+        let (client, _eventloop) = client();
+        let queue = OfflineQueue::open(path.clone(), 10);
+        publish(&queue, &client, 0..10);
+        assert_eq!(queue.state.lock().unwrap().dropped, 0);
+
+        // Die elfte Nachricht kürzt auf 90 %
+        publish(&queue, &client, 10..11);
+        let state = queue.state.lock().unwrap();
+        assert_eq!(state.len, 9);
+        assert_eq!(state.dropped, 2);
+        drop(state);
+        let expected: Vec<String> = (2..11).map(|i| format!("m{}", i)).collect();
+        assert_eq!(topics(&queue), expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn without_buffer_nothing_is_stored() {
+        let path = queue_path("disabled");
+        let (client, _eventloop) = client();
+        let queue = OfflineQueue::open(path.clone(), 0);
+        publish(&queue, &client, 0..3);
+        assert_eq!(queue.state.lock().unwrap().len, 0);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn drain_sends_in_order() {
+        let path = queue_path("drain");
+        let (client, mut eventloop) = client();
+        let queue = OfflineQueue::open(path.clone(), 100);
+        publish(&queue, &client, 0..3);
+
+        queue.set_connected(true);
+        queue.start_drain(&client);
+        for _ in 0..100 {
+            if !queue.state.lock().unwrap().draining {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(queue.state.lock().unwrap().len, 0);
+        assert!(topics(&queue).is_empty());
+
+        // Verbunden und leer: direkt an den Client
+        publish(&queue, &client, 3..4);
+        assert_eq!(queue.state.lock().unwrap().len, 0);
+
+        eventloop.clean();
+        let sent: Vec<String> = eventloop
+            .pending
+            .iter()
+            .map(|request| match request {
+                Request::Publish(publish) => publish.topic.clone(),
+                other => panic!("unexpected request {:?}", other),
+            })
+            .collect();
+        assert_eq!(sent, ["m0", "m1", "m2", "m3"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
-use crate::config::Config;
+use crate::config::{Config, MeterConfig};
+use crate::sink::{Reading, Sink};
+use async_trait::async_trait;
 //use chrono::format::Numeric;
 use chrono::{Local, Timelike, Utc};
 use tokio::time::sleep;
@@ -15,6 +17,7 @@ use rrd::{
 };
 use std::error::Error;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use tracing::{error, info};
 
 enum Language {
@@ -22,9 +25,9 @@ enum Language {
     En,
 }
 
-pub fn save_rrd_on_shutdown(config: &Config) {
-    if Path::new(&config.rrd_path).exists() {
-        match std::fs::copy(&config.rrd_path, &config.rrd_backup_path) {
+pub fn save_rrd_on_shutdown(meter: &MeterConfig) {
+    if Path::new(&meter.rrd_path).exists() {
+        match std::fs::copy(&meter.rrd_path, &meter.rrd_backup_path) {
             Ok(bytes_copied) => {
                 info!(
                     "RRD database backup successful. Copied {} bytes.",
@@ -38,10 +41,50 @@ pub fn save_rrd_on_shutdown(config: &Config) {
     }
 }
 
-pub fn ensure_rrd(config: &Config) {
-    if !Path::new(&config.rrd_path).exists() {
+/// Sicherung auf Anforderung (MQTT-Befehl `backup_rrd`)
+pub fn backup_rrd(meter: &MeterConfig) -> Result<String, String> {
+    std::fs::copy(&meter.rrd_path, &meter.rrd_backup_path)
+        .map(|bytes| {
+            format!(
+                "copied {} bytes to {}",
+                bytes,
+                meter.rrd_backup_path.display()
+            )
+        })
+        .map_err(|e| format!("error backing up RRD database: {}", e))
+}
+
+/// Alle Graphen (Stunde, Tag, Woche) sofort neu erzeugen (MQTT-Befehl `regenerate_graphs`)
+pub fn regenerate_graphs(meter: &MeterConfig) -> Result<String, String> {
+    let graphs = [
+        ("strom-stunde-de.png", GraphPeriod::Hour, Language::De),
+        ("strom-stunde-en.png", GraphPeriod::Hour, Language::En),
+        ("strom-tag-de.png", GraphPeriod::Day, Language::De),
+        ("strom-tag-en.png", GraphPeriod::Day, Language::En),
+        ("strom-woche-de.png", GraphPeriod::Week, Language::De),
+        ("strom-week-en.png", GraphPeriod::Week, Language::En),
+    ];
+    let count = graphs.len();
+    let _ = std::fs::create_dir_all(&meter.image_dir);
+    for (file, period, language) in graphs {
+        generate_graph(
+            meter.rrd_path.clone(),
+            &meter.image_dir.join(file),
+            period,
+            language,
+        )
+        .map_err(|e| format!("error generating {}: {}", file, e))?;
+    }
+    Ok(format!("{} graphs generated", count))
+}
+
+/// Legt die RRD an, falls weder sie noch eine Sicherung existiert. Auflösung,
+/// Heartbeat und Archivgrößen kommen aus `--rrd-step`, `--rrd-heartbeat`,
+/// `--rrd-rows` und `--rrd-hourly-rows`.
+pub fn ensure_rrd(meter: &MeterConfig, config: &Config) {
+    if !Path::new(&meter.rrd_path).exists() {
         // file present?
-        if let Some(parent_dir) = Path::new(&config.rrd_path).parent() {
+        if let Some(parent_dir) = Path::new(&meter.rrd_path).parent() {
             // 2. Check if the parent directory exists
             if !parent_dir.exists() {
                 // 3. Create the directory (and any necessary parents)
@@ -56,9 +99,9 @@ pub fn ensure_rrd(config: &Config) {
             }
         }
 
-        if Path::new(&config.rrd_backup_path).exists() {
+        if Path::new(&meter.rrd_backup_path).exists() {
             info!("Restoring RRD from backup...");
-            match std::fs::copy(&config.rrd_backup_path, &config.rrd_path) {
+            match std::fs::copy(&meter.rrd_backup_path, &meter.rrd_path) {
                 Ok(bytes_copied) => {
                     info!(
                         "RRD database backup restored. Copied {} bytes.",
@@ -70,74 +113,114 @@ pub fn ensure_rrd(config: &Config) {
                 }
             }
         } else {
-            info!("Creating new RRD database: {}", config.rrd_path.display());
+            info!("Creating new RRD database: {}", meter.rrd_path.display());
+            let heartbeat = u32::try_from(config.rrd_heartbeat.as_secs()).unwrap_or(u32::MAX);
+            // Von `Config::validate` geprüft: ganze Sekunden, teilt eine Stunde
+            let hourly_steps = (3600 / config.rrd_step.as_secs().max(1)) as u32;
             let _ = create::create(
-                &config.rrd_path,
+                &meter.rrd_path,
                 chrono::Utc::now(),
-                Duration::from_secs(5),
+                config.rrd_step,
                 false,
                 None,
                 &[],
                 &[
                     create::DataSource::counter(
                         create::DataSourceName::new("Bezug"),
-                        900,
+                        heartbeat,
                         Some(0),
                         Some(10000000000),
                     ),
                     create::DataSource::counter(
                         create::DataSourceName::new("Einspeisung"),
-                        900,
+                        heartbeat,
                         Some(0),
                         Some(10000000000),
                     ),
                     create::DataSource::gauge(
                         create::DataSourceName::new("Wirkleistung"),
-                        900,
+                        heartbeat,
                         Some(-10000.0),
                         Some(100000.0),
                     ),
                 ],
                 &[
-                    create::Archive::new(ConsolidationFn::Avg, 0.5, 1, 720 * 24).unwrap(),
-                    create::Archive::new(ConsolidationFn::Avg, 0.5, 720, 8760 * 2).unwrap(),
+                    create::Archive::new(ConsolidationFn::Avg, 0.5, 1, config.rrd_rows).unwrap(),
+                    create::Archive::new(
+                        ConsolidationFn::Avg,
+                        0.5,
+                        hourly_steps,
+                        config.rrd_hourly_rows,
+                    )
+                    .unwrap(),
                 ],
             );
         }
     }
 }
 
-pub fn update_rrd(path: &Path, bezug: u64, einspeisung: u64, wirkleistung: i32) {
+/// Fehlgeschlagene `update_rrd`-Aufrufe aller Zähler (für `/metrics`)
+static UPDATE_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+pub fn update_errors() -> u64 {
+    UPDATE_ERRORS.load(Ordering::Relaxed)
+}
+
+/// Schreibt jeden Messwert in die RRD des Zählers (`MeterConfig::rrd_path`)
+pub struct RrdSink;
+
+#[async_trait]
+impl Sink for RrdSink {
+    fn name(&self) -> &'static str {
+        "rrd"
+    }
+
+    async fn reading(&mut self, reading: &Reading<'_>) {
+        update_rrd(
+            &reading.meter.rrd_path,
+            reading.bezug,
+            reading.einspeisung,
+            reading.wirkleistung,
+        );
+    }
+}
+
+/// Schreibt die Werte (Wh bzw. W) in die RRD. Die Datenquellen behalten ihre
+/// bisherige Auflösung, damit bestehende Datenbanken und Graphen weiter passen:
+/// Bezug in 0,1 Wh, Einspeisung in 1/18 Wh, Wirkleistung in 0,1 W.
+pub fn update_rrd(path: &Path, bezug_wh: f64, einspeisung_wh: f64, wirkleistung_w: f64) {
+    let bezug = (bezug_wh * 10.0).round() as u64;
+    let einspeisung = (einspeisung_wh * 18.0) as u64;
+    let wirkleistung = (wirkleistung_w * 10.0).round();
     let rc = update::update_all(
         path,
         update::ExtraFlags::empty(),
         &[(
             update::BatchTime::Now,
-            &[
-                bezug.into(),
-                einspeisung.into(),
-                (wirkleistung as f64).into(),
-            ],
+            &[bezug.into(), einspeisung.into(), wirkleistung.into()],
         )],
     );
     match rc {
         Ok(_) => info!("Ok"),
-        Err(err) => error!("Not ok: {err}"),
+        Err(err) => {
+            UPDATE_ERRORS.fetch_add(1, Ordering::Relaxed);
+            error!("Not ok: {err}")
+        }
     }
 }
 
-pub async fn run_graph_loop(config: Config, token: CancellationToken) {
+pub async fn run_graph_loop(meter: MeterConfig, interval: Duration, token: CancellationToken) {
     let mut last_hour = Local::now().hour();
-    info!("Starting native graph generator loop");
+    info!("Starting native graph generator loop for {}", meter.name);
 
-    if !Path::new(&config.image_output_path).exists() {
-        let _ = std::fs::create_dir_all(&config.image_output_path);
+    if !meter.image_dir.exists() {
+        let _ = std::fs::create_dir_all(&meter.image_dir);
     }
 
     loop {
         tokio::select! {
-            // Option 1: Warte 30 Sekunden
-            _ = sleep(Duration::from_secs(30)) => {
+            // Option 1: Warte das eingestellte Intervall (Standard 30 Sekunden)
+            _ = sleep(interval) => {
                 // Führe nach dem Sleep den Haupt-Code aus
             }
             // Option 2: Warte auf das Abbruch-Token
@@ -149,11 +232,11 @@ pub async fn run_graph_loop(config: Config, token: CancellationToken) {
         let now = Local::now();
         let current_hour = now.hour();
 
-        let path_hour_de = format!("{}/strom-stunde-de.png", config.image_output_path);
-        let path_hour_en = format!("{}/strom-stunde-en.png", config.image_output_path);
+        let path_hour_de = meter.image_dir.join("strom-stunde-de.png");
+        let path_hour_en = meter.image_dir.join("strom-stunde-en.png");
 
         if let Err(e) = generate_graph(
-            config.rrd_path.clone(),
+            meter.rrd_path.clone(),
             &path_hour_de,
             GraphPeriod::Hour,
             Language::De,
@@ -161,7 +244,7 @@ pub async fn run_graph_loop(config: Config, token: CancellationToken) {
             error!("Error generating hourly graph (DE): {}", e);
         }
         if let Err(e) = generate_graph(
-            config.rrd_path.clone(),
+            meter.rrd_path.clone(),
             &path_hour_en,
             GraphPeriod::Hour,
             Language::En,
@@ -172,7 +255,7 @@ pub async fn run_graph_loop(config: Config, token: CancellationToken) {
         if current_hour != last_hour {
             if current_hour == 0 {
                 info!("Backing up RRD database");
-                match std::fs::copy(&config.rrd_path, &config.rrd_backup_path) {
+                match std::fs::copy(&meter.rrd_path, &meter.rrd_backup_path) {
                     Ok(bytes_copied) => {
                         info!(
                             "RRD database backup successful. Copied {} bytes.",
@@ -185,11 +268,11 @@ pub async fn run_graph_loop(config: Config, token: CancellationToken) {
                 }
             }
             info!("Generating day graph");
-            let path_day_de = format!("{}/strom-tag-de.png", config.image_output_path);
-            let path_day_en = format!("{}/strom-tag-en.png", config.image_output_path);
+            let path_day_de = meter.image_dir.join("strom-tag-de.png");
+            let path_day_en = meter.image_dir.join("strom-tag-en.png");
 
             if let Err(e) = generate_graph(
-                config.rrd_path.clone(),
+                meter.rrd_path.clone(),
                 &path_day_de,
                 GraphPeriod::Day,
                 Language::De,
@@ -197,7 +280,7 @@ pub async fn run_graph_loop(config: Config, token: CancellationToken) {
                 error!("Error generating daily graph (DE): {}", e);
             }
             if let Err(e) = generate_graph(
-                config.rrd_path.clone(),
+                meter.rrd_path.clone(),
                 &path_day_en,
                 GraphPeriod::Day,
                 Language::En,
@@ -207,11 +290,11 @@ pub async fn run_graph_loop(config: Config, token: CancellationToken) {
 
             if current_hour == 1 {
                 info!("Generating week graph");
-                let path_week_de = format!("{}/strom-woche-de.png", config.image_output_path);
-                let path_week_en = format!("{}/strom-week-en.png", config.image_output_path);
+                let path_week_de = meter.image_dir.join("strom-woche-de.png");
+                let path_week_en = meter.image_dir.join("strom-week-en.png");
 
                 if let Err(e) = generate_graph(
-                    config.rrd_path.clone(),
+                    meter.rrd_path.clone(),
                     &path_week_de,
                     GraphPeriod::Week,
                     Language::De,
@@ -219,7 +302,7 @@ pub async fn run_graph_loop(config: Config, token: CancellationToken) {
                     error!("Error generating daily graph (DE): {}", e);
                 }
                 if let Err(e) = generate_graph(
-                    config.rrd_path.clone(),
+                    meter.rrd_path.clone(),
                     &path_week_en,
                     GraphPeriod::Week,
                     Language::En,
@@ -258,7 +341,7 @@ fn calculate_duration(period: GraphPeriod) -> Duration {
 
 fn generate_graph(
     rrd_file: PathBuf,
-    output_file: &str,
+    output_file: &Path,
     period: GraphPeriod,
     lang: Language,
 ) -> Result<(), Box<dyn Error>> {
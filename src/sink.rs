@@ -0,0 +1,38 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+
+use crate::config::MeterConfig;
+use crate::obis::Registers;
+use crate::stats::FrameStats;
+
+/// Ein verarbeiteter Messwert, so wie ihn alle Ausgaben erhalten
+pub struct Reading<'a> {
+    pub meter: &'a MeterConfig,
+    /// Ablesezeitpunkt
+    pub time: DateTime<Local>,
+    /// Zählerstand Bezug (Wh)
+    pub bezug: f64,
+    /// Einspeisung (Wh), aus 2.8.0 oder integriert
+    pub einspeisung: f64,
+    /// Wirkleistung (W), negativ bei Einspeisung
+    pub wirkleistung: f64,
+    pub feed_in: bool,
+    /// `feed_in` hat sich mit diesem Messwert geändert
+    pub feed_in_changed: bool,
+    pub registers: &'a Registers,
+}
+
+/// Ausgabe für die Messwerte eines Zählers (RRD, MQTT, ...). Jede
+/// Eingabeschleife hat ihre eigenen Ausgaben; sie dürfen nicht lange
+/// blockieren, sonst stauen sich die Daten der Quelle.
+#[async_trait]
+pub trait Sink: Send {
+    /// Bezeichnung für Logausgaben
+    fn name(&self) -> &'static str;
+
+    /// Neuer Messwert
+    async fn reading(&mut self, reading: &Reading<'_>);
+
+    /// Frame-Statistik, alle `STATS_INTERVAL`
+    async fn stats(&mut self, _meter: &MeterConfig, _stats: &FrameStats) {}
+}
@@ -1,374 +1,621 @@
 use chrono::Local;
-use rumqttc::{AsyncClient, QoS};
 //use std::f32::consts::PI;
-use std::io::Read;
 //use sml_rs::transport::SmlMessages;
-use std::path::Path;
 use std::time::{Duration, Instant};
-use std::{str, string};
 
-use tokio::time::sleep;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant as TokioInstant};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, Level};
+use tracing::{error, info, warn, Level};
 // use sml_rs::parser::ParseError;
-// use sml_rs::parser::common::Value;
+// use sml_rs::parser::common::{Status, Value};
 use sml_rs::parser::complete::File as SmlFile;
+use sml_rs::parser::ParseError;
 // use sml_rs::parser::complete::MessageBody::CloseResponse;
 // use sml_rs::parser::complete::MessageBody::GetListResponse;
-use sml_rs::parser::common::Value;
+use sml_rs::parser::common::{Status, Value};
+use sml_rs::transport::DecodeErr;
 // wichtig
 
-use crate::config::Config;
+use crate::capture::Recorder;
+use crate::command::{MeterCommand, MeterRequest};
+use crate::config::{Config, MeterConfig};
+use crate::d0;
 use crate::model::{SensorData, SharedAppState, SseData};
-use crate::rrd::update_rrd;
+use crate::obis::{self, ObisCode, Register, RegisterValue, Registers};
+use crate::persist;
+use crate::profile::Protocol;
+use crate::sink::{Reading, Sink};
+use crate::source;
+use crate::stats::STATS_INTERVAL;
 
-struct BitsNStrings {
-    obis: &'static [u8; 6],
-    name: &'static str,
-}
-
-const OBIS_ZAEHLERSTAND: &[u8] = &[1, 0, 1, 8, 0, 255];
-const OBIS_WIRKLEISTUNG: &[u8] = &[1, 0, 16, 7, 0, 255];
+/// Register, die so lange in keinem Telegramm mehr vorkommen, werden verworfen
+const REGISTER_EXPIRY: Duration = Duration::from_secs(3600);
 
-const OBIS: [BitsNStrings; 11] = [
-    {
-        BitsNStrings {
-            obis: &[1, 0, 0, 0, 1, 255],
-            name: &"Seriennummer",
-        }
-    },
-    {
-        BitsNStrings {
-            obis: &[1, 0, 0, 0, 9, 255],
-            name: &"Geräteeinzelidentifikation",
-        }
-    },
-    {
-        BitsNStrings {
-            obis: &[1, 0, 1, 8, 0, 255],
-            name: &"Zählerstand Bezug",
-        }
-    },
-    {
-        BitsNStrings {
-            obis: &[1, 0, 1, 8, 1, 255],
-            name: &"Bezug Tarif 1",
-        }
-    },
-    {
-        BitsNStrings {
-            obis: &[1, 0, 1, 8, 2, 255],
-            name: &"Bezug Tarif 2",
-        }
-    },
-    {
-        BitsNStrings {
-            obis: &[1, 0, 16, 7, 0, 255],
-            name: &"Leistung",
-        }
-    },
-    {
-        BitsNStrings {
-            obis: &[1, 0, 36, 7, 0, 255],
-            name: &"Leistung an L1",
-        }
-    },
-    {
-        BitsNStrings {
-            obis: &[1, 0, 56, 7, 0, 255],
-            name: &"Leistung an L2",
-        }
-    },
-    {
-        BitsNStrings {
-            obis: &[1, 0, 76, 7, 0, 255],
-            name: &"Leistung an L3",
-        }
-    },
-    {
-        BitsNStrings {
-            obis: &[129, 129, 199, 130, 3, 255],
-            name: &"Herstelleridentifikation",
-        }
-    },
-    {
-        BitsNStrings {
-            obis: &[129, 129, 199, 130, 5, 255],
-            name: &"Public Key",
-        }
-    },
-];
-
-pub async fn run_serial_loop(
+pub async fn run_input_loop(
     config: Config,
+    mut meter: MeterConfig,
     app_state: SharedAppState,
-    mqtt_client: AsyncClient,
+    mut sinks: Vec<Box<dyn Sink>>,
+    mut commands: mpsc::Receiver<MeterRequest>,
     token: CancellationToken,
 ) {
+    let mut stats_interval = STATS_INTERVAL;
+    let mut source = source::from_input(
+        &meter.input,
+        meter.serial,
+        meter.protocol,
+        meter.read_timeout,
+    );
     let mut sensor = SensorData::new();
+    if let Some(profile) = meter.profile {
+        info!(
+            "Meter {}: profile {}: {}",
+            meter.name, profile.name, profile.description
+        );
+    }
+    sensor.expected_obis = meter.expected_obis();
+    match &meter.state_path {
+        Some(path) => {
+            if let Some(saved) = persist::load(path) {
+                sensor.einspeisung = saved.einspeisung;
+                sensor.restored = Some(saved);
+            }
+        }
+        None => info!(
+            "Meter {}: capture input, state is not persisted",
+            meter.name
+        ),
+    }
 
     // let buf = ArrayBuf::<4069>::default();
     // let mut decoder = sml_rs::transport::Decoder::from_buf(buf);
 
-    let mut decoder = sml_rs::transport::Decoder::<Vec<u8>>::new();
+    let protocol = meter.protocol;
+    let mut decoder = FrameDecoder::new(protocol);
 
-    info!("Starting SML Reader Loop on {}", config.serial_port);
+    let name = source.describe();
+    info!(
+        "Starting {:?} Reader Loop for meter {} on {}",
+        protocol, meter.name, name
+    );
+
+    let mut recorder = match &meter.record {
+        Some(path) => match Recorder::open(path) {
+            Ok(r) => {
+                info!("Recording raw frames to {}", path.display());
+                Some(r)
+            }
+            Err(e) => {
+                error!("Error opening capture file {}: {}", path.display(), e);
+                None
+            }
+        },
+        None => None,
+    };
 
-    loop {
+    'input: loop {
         if token.is_cancelled() {
             break;
         }
-        let mut port = match serialport::new(&config.serial_port, 9600)
-            .timeout(Duration::from_secs(5))
-            .open()
-        {
-            Ok(p) => {
-                info!("Serial interface {} opened", config.serial_port);
-                p
-            }
-            Err(e) => {
+        match source.open().await {
+            Ok(()) => info!("Input {} opened", name),
+            Err(e) if source.restartable() => {
                 error!(
-                    "Error opening {}: {}. Retrying in 5s...",
-                    config.serial_port, e
+                    "Error opening {}: {}. Retrying in {:?}...",
+                    name, e, meter.reconnect_delay
                 );
-                tokio::select! {
-                    // Option 1: Warte 30 Sekunden
-                    _ =  sleep(Duration::from_secs(5)) => {
-                        // Führe nach dem Sleep den Haupt-Code aus
-                    }
-                    // Option 2: Warte auf das Abbruch-Token
-                    _ = token.cancelled() => {
-                        info!("Graph loop received cancellation signal. Exiting.");
-                        break; // Schleife verlassen und Funktion beenden
+                let retry_at = TokioInstant::now() + meter.reconnect_delay;
+                loop {
+                    tokio::select! {
+                        // Option 1: Warte die eingestellte Zeit
+                        _ = sleep_until(retry_at) => break,
+                        // Befehle auch ohne Quelle beantworten
+                        Some(request) = commands.recv() => {
+                            handle_request(request, &mut sensor, &mut meter, &mut stats_interval);
+                        }
+                        // Option 2: Warte auf das Abbruch-Token
+                        _ = token.cancelled() => {
+                            info!("Input loop received cancellation signal. Exiting.");
+                            break 'input; // Schleife verlassen und Funktion beenden
+                        }
                     }
                 }
+                // Auch ohne Quelle, damit das Alter des letzten Messwerts weiterläuft
+                let stats_due = sensor
+                    .last_stats_publish
+                    .is_none_or(|t| t.elapsed() >= stats_interval);
+                if stats_due {
+                    publish_stats(&mut sensor, &mut sinks, &app_state, &meter).await;
+                }
                 continue;
             }
-        };
-
-        let mut serial_buf = [0u8; 256];
+            Err(e) => {
+                error!("Error opening {}: {}", name, e);
+                break;
+            }
+        }
 
         loop {
-            if token.is_cancelled() {
-                info!("Close Port {}...", config.serial_port);
-                return;
-            }
-            match port.read(&mut serial_buf) {
-                Ok(n) if n > 0 => {
-                    for &byte in &serial_buf[..n] {
-                        match decoder.push_byte(byte) {
-                            Ok(Some(decoded_bytes)) => {
-                                match sml_rs::parser::complete::parse(decoded_bytes) {
-                                    Ok(message) => {
-                                        process_sml_messages(
-                                            Some(message),
-                                            &mut sensor,
-                                            &mqtt_client,
-                                            &app_state,
-                                            &config.rrd_path,
-                                        )
-                                        .await;
-                                    }
-                                    Err(e) => {
-                                        error!("Parsing error: {:?}", e);
-                                        //None
-                                    }
-                                };
-                            }
-                            Ok(None) => {}
-                            Err(e) => {
-                                error!("Decode Error: {:?}", e);
-                            }
+            // Statistik auch ohne Frames veröffentlichen (abgefallener Lesekopf)
+            let stats_due = sensor
+                .last_stats_publish
+                .map_or_else(Instant::now, |t| t + stats_interval);
+            let read = tokio::select! {
+                r = source.read() => r,
+                _ = sleep_until(stats_due.into()) => {
+                    publish_stats(&mut sensor, &mut sinks, &app_state, &meter).await;
+                    continue;
+                }
+                // MQTT-Befehle sofort, auch wenn der Zähler gerade nichts sendet
+                Some(request) = commands.recv() => {
+                    handle_request(request, &mut sensor, &mut meter, &mut stats_interval);
+                    continue;
+                }
+                _ = token.cancelled() => {
+                    info!("Close input {}...", name);
+                    break 'input;
+                }
+            };
+            match read {
+                Ok(Some(bytes)) => {
+                    sensor.capture_time = source.capture_time().map(|t| t.with_timezone(&Local));
+                    process_bytes(
+                        &bytes,
+                        &mut decoder,
+                        &mut recorder,
+                        &mut sensor,
+                        &mut sinks,
+                        &app_state,
+                        &meter,
+                    )
+                    .await;
+
+                    let checkpoint_due = sensor
+                        .last_checkpoint
+                        .is_none_or(|t| t.elapsed() >= config.state_interval);
+                    if checkpoint_due {
+                        if let Some(path) = &meter.state_path {
+                            persist::save(path, &sensor);
                         }
+                        sensor.last_checkpoint = Some(Instant::now());
                     }
                 }
-                Ok(_) => {}
+                Ok(None) => {
+                    info!("Input {} reached end of stream", name);
+                    break;
+                }
                 Err(e) => {
-                    error!("Serial Port Read Error: {:?}", e);
+                    error!("Input {} Read Error: {:?}", name, e);
                     break;
                 }
             }
-            sleep(Duration::from_millis(10)).await;
+        }
+
+        if !source.restartable() {
+            info!("Input {} finished", name);
+            break;
         }
     }
+
+    if let Some(path) = &meter.state_path {
+        persist::save(path, &sensor);
+    }
 }
 
-// HIER IST DIE KORRIGIERTE FUNKTION:
-async fn process_sml_messages(
-    messages: Option<SmlFile<'_>>,
+/// Beantwortet einen MQTT-Befehl, sofern noch jemand auf die Antwort wartet
+fn handle_request(
+    request: MeterRequest,
     sensor: &mut SensorData,
-    client: &AsyncClient,
-    app_state: &SharedAppState,
-    rrd_path: &Path,
+    meter: &mut MeterConfig,
+    stats_interval: &mut Duration,
 ) {
-    let mut found_data = false;
+    if request.reply.is_closed() {
+        return;
+    }
+    let result = apply_command(request.command, sensor, meter, stats_interval);
+    let _ = request.reply.send(result);
+}
+
+/// Führt einen MQTT-Befehl auf dem Zustand der Eingabeschleife aus
+fn apply_command(
+    command: MeterCommand,
+    sensor: &mut SensorData,
+    meter: &mut MeterConfig,
+    stats_interval: &mut Duration,
+) -> Result<String, String> {
+    match command {
+        MeterCommand::ResetFeedIn => {
+            if sensor.einspeisung_vom_zaehler {
+                return Err("feed-in counter is read from register 2.8.0".to_string());
+            }
+            info!(
+                "{}: Einspeisung zurückgesetzt (war {:.1} Wh)",
+                meter.name, sensor.einspeisung
+            );
+            sensor.einspeisung = 0.0;
+            if let Some(path) = &meter.state_path {
+                persist::save(path, sensor);
+            }
+            sensor.last_checkpoint = Some(Instant::now());
+            Ok("feed-in counter reset".to_string())
+        }
+        MeterCommand::SetPublishInterval(interval) => {
+            meter.mqtt_publish_interval = interval;
+            Ok(format!("publish interval {:?}", interval))
+        }
+        MeterCommand::SetStatsInterval(interval) => {
+            *stats_interval = interval;
+            Ok(format!("stats interval {:?}", interval))
+        }
+    }
+}
 
-    if let Some(sml_file) = messages {
-        for msg in sml_file.messages {
-            if let sml_rs::parser::complete::MessageBody::GetListResponse(list_response) =
-                msg.message_body
-            {
-                for val in list_response.val_list {
-                    if tracing::event_enabled!(Level::INFO) {
-                        // OBIS Code prüfen
-                        for ob in OBIS {
-                            if val.obj_name == ob.obis.as_ref() {
-                                let v: String = match val.value {
-                                    Value::I32(i) => i.to_string(),
-                                    Value::I64(i) => i.to_string(),
-                                    Value::Bytes(b) => {
-                                        b.iter().map(|b| format!("{:02x}", b)).collect::<String>()
-                                    }
-                                    Value::List(_) => "".to_string(),
-                                    _ => "".to_string(),
-                                };
-                                info!("get: {} {}", ob.name, v);
-                                //error!("get: {} {}", ob.name, v);
+/// Dekoder für das jeweilige Zählerprotokoll
+enum FrameDecoder {
+    Sml(sml_rs::transport::Decoder<Vec<u8>>),
+    D0(d0::Decoder),
+}
+
+impl FrameDecoder {
+    fn new(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Sml => FrameDecoder::Sml(sml_rs::transport::Decoder::<Vec<u8>>::new()),
+            Protocol::D0 { .. } => FrameDecoder::D0(d0::Decoder::new()),
+        }
+    }
+}
+
+/// Schiebt die empfangenen Rohdaten durch den Dekoder des Protokolls und
+/// verarbeitet jedes vollständig dekodierte Telegramm.
+async fn process_bytes(
+    bytes: &[u8],
+    decoder: &mut FrameDecoder,
+    recorder: &mut Option<Recorder>,
+    sensor: &mut SensorData,
+    sinks: &mut [Box<dyn Sink>],
+    app_state: &SharedAppState,
+    meter: &MeterConfig,
+) {
+    for &byte in bytes {
+        let registers = match decoder {
+            FrameDecoder::Sml(decoder) => {
+                let result = decoder.push_byte(byte);
+                record_byte(recorder, byte, !matches!(result, Ok(None)));
+                match result {
+                    Ok(Some(decoded_bytes)) => {
+                        sensor.stats.frames_received += 1;
+                        match sml_rs::parser::complete::parse(decoded_bytes) {
+                            Ok(message) => Some(sml_registers(message)),
+                            // sml-rs kennt nur Open-, Close- und GetListResponse
+                            Err(ParseError::UnexpectedVariant) => {
+                                sensor.stats.unknown_messages += 1;
+                                warn!("SML frame with unsupported message type");
+                                None
+                            }
+                            Err(e) => {
+                                sensor.stats.parse_errors += 1;
+                                error!("Parsing error: {:?}", e);
+                                None
                             }
                         }
                     }
-                    if val.obj_name == OBIS_ZAEHLERSTAND {
-                        match val.value {
-                            Value::I64(v) => {
-                                update_zaehlerstand(sensor, v as u64);
-                                found_data = true;
-                            }
-                            Value::U64(v) => {
-                                update_zaehlerstand(sensor, v);
-                                found_data = true;
-                            }
-                            _ => {}
+                    Ok(None) => None,
+                    Err(DecodeErr::DiscardedBytes(n)) => {
+                        sensor.stats.discarded_bytes += n as u64;
+                        warn!("Discarded {} bytes outside of an SML frame", n);
+                        None
+                    }
+                    Err(e) => {
+                        match e {
+                            DecodeErr::InvalidMessage {
+                                checksum_mismatch: (got, expected),
+                                ..
+                            } if got != expected => sensor.stats.checksum_errors += 1,
+                            _ => sensor.stats.decode_errors += 1,
                         }
-                    } else if val.obj_name == OBIS_WIRKLEISTUNG {
-                        match val.value {
-                            Value::I64(v) => {
-                                sensor.wirkleistung = v as i32;
-                                found_data = true;
-                            }
-                            Value::I32(v) => {
-                                sensor.wirkleistung = v;
-                                found_data = true;
-                            }
-                            _ => {}
+                        error!("Decode Error: {:?}", e);
+                        None
+                    }
+                }
+            }
+            FrameDecoder::D0(decoder) => {
+                let result = decoder.push_byte(byte);
+                record_byte(recorder, byte, !matches!(result, Ok(None)));
+                match result {
+                    Ok(Some(telegram)) => {
+                        sensor.stats.frames_received += 1;
+                        let registers = d0::parse(&telegram);
+                        if registers.is_empty() {
+                            sensor.stats.parse_errors += 1;
+                            error!("D0 telegram without data lines");
                         }
+                        Some(registers)
+                    }
+                    Ok(None) => None,
+                    Err(e) => {
+                        sensor.stats.decode_errors += 1;
+                        error!("D0 Decode Error: {:?}", e);
+                        None
                     }
                 }
             }
+        };
+        if let Some(registers) = registers {
+            process_registers(registers, sensor, sinks, app_state, meter).await;
+        }
+    }
+}
+
+fn record_byte(recorder: &mut Option<Recorder>, byte: u8, frame_end: bool) {
+    if let Some(rec) = recorder.as_mut() {
+        rec.push(byte);
+        if frame_end {
+            if let Err(e) = rec.finish_frame() {
+                error!("Error writing capture file: {}", e);
+            }
+        }
+    }
+}
+
+/// Sammelt alle Register aus den `GetListResponse`-Nachrichten einer SML-Datei.
+fn sml_registers(sml_file: SmlFile<'_>) -> Registers {
+    use sml_rs::parser::complete::MessageBody;
+
+    let mut registers = Registers::new();
+    for msg in sml_file.messages {
+        let list_response = match msg.message_body {
+            MessageBody::GetListResponse(list_response) => list_response,
+            MessageBody::OpenResponse(_) | MessageBody::CloseResponse(_) => continue,
+        };
+        for val in list_response.val_list {
+            let value = match val.value {
+                Value::Bool(b) => RegisterValue::Int(b as i64),
+                Value::I8(i) => RegisterValue::Int(i as i64),
+                Value::I16(i) => RegisterValue::Int(i as i64),
+                Value::I32(i) => RegisterValue::Int(i as i64),
+                Value::I64(i) => RegisterValue::Int(i),
+                Value::U8(i) => RegisterValue::Int(i as i64),
+                Value::U16(i) => RegisterValue::Int(i as i64),
+                Value::U32(i) => RegisterValue::Int(i as i64),
+                Value::U64(i) => RegisterValue::Int(i as i64),
+                Value::Bytes(b) => RegisterValue::Bytes(b.to_vec()),
+                Value::List(_) => continue,
+            };
+            let status = val.status.map(|s| match s {
+                Status::Status8(s) => s as u64,
+                Status::Status16(s) => s as u64,
+                Status::Status32(s) => s as u64,
+                Status::Status64(s) => s,
+            });
+            if let Some(code) = ObisCode::from_slice(val.obj_name) {
+                registers.insert(
+                    code,
+                    Register {
+                        value,
+                        scaler: val.scaler,
+                        unit: val.unit,
+                        status,
+                    },
+                );
+            }
         }
     }
+    registers
+}
+
+// HIER IST DIE KORRIGIERTE FUNKTION:
+async fn process_registers(
+    registers: Registers,
+    sensor: &mut SensorData,
+    sinks: &mut [Box<dyn Sink>],
+    app_state: &SharedAppState,
+    meter: &MeterConfig,
+) {
+    if registers.is_empty() {
+        return;
+    }
+    check_expected_obis(sensor, &registers);
+
+    if tracing::event_enabled!(Level::INFO) {
+        for (code, register) in &registers {
+            match code.name() {
+                Some(name) => info!("get: {} {}", name, register),
+                None => info!("get: {} {}", code, register),
+            }
+        }
+    }
+
+    let mut found_data = false;
+    if let Some(wh) = registers
+        .get(&obis::ZAEHLERSTAND)
+        .and_then(Register::scaled)
+    {
+        persist::validate_restored(sensor, wh);
+        update_zaehlerstand(sensor, wh);
+        found_data = true;
+    }
+    if let Some(w) = obis::wirkleistung(&registers) {
+        sensor.wirkleistung = w;
+        found_data = true;
+    }
+    // Zweirichtungszähler liefern die Einspeisung direkt, dann wird nicht integriert
+    match registers.get(&obis::EINSPEISUNG).and_then(Register::scaled) {
+        Some(wh) => {
+            if !sensor.einspeisung_vom_zaehler {
+                info!("Meter reports export register 2.8.0, using it instead of integrating power");
+                sensor.einspeisung_vom_zaehler = true;
+            }
+            sensor.einspeisung = wh;
+        }
+        None if sensor.einspeisung_vom_zaehler => {
+            warn!("Export register 2.8.0 missing, falling back to integrating power");
+            sensor.einspeisung_vom_zaehler = false;
+        }
+        None => {}
+    }
+    merge_registers(sensor, registers, Instant::now());
 
     if found_data {
-        handle_logic_update(sensor, client, app_state, rrd_path).await;
+        sensor.stats.valid_readings += 1;
+        sensor.stats.last_valid = Some(Instant::now());
+        handle_logic_update(sensor, sinks, app_state, meter).await;
+    }
+}
+
+/// Übernimmt die Register eines Telegramms. Nicht jeder Zähler sendet in jedem
+/// Telegramm alle Werte; fehlende Register bleiben erhalten und entfallen erst,
+/// wenn sie `REGISTER_EXPIRY` lang nicht mehr gemeldet wurden. So bleiben die
+/// Discovery-Entitäten stabil.
+fn merge_registers(sensor: &mut SensorData, registers: Registers, now: Instant) {
+    for code in registers.keys() {
+        sensor.registers_seen.insert(*code, now);
     }
+    sensor
+        .registers_seen
+        .retain(|_, seen| now.duration_since(*seen) < REGISTER_EXPIRY);
+    sensor.registers.extend(registers);
+    let seen = &sensor.registers_seen;
+    sensor.registers.retain(|code, _| seen.contains_key(code));
 }
 
-fn update_zaehlerstand(sensor: &mut SensorData, val: u64) {
-    if sensor.zaehlerstand_alt == 0 {
-        sensor.zaehlerstand_alt = val;
+/// Meldet einmalig, welche vom Zählerprofil erwarteten OBIS-Kennzahlen fehlen.
+fn check_expected_obis(sensor: &mut SensorData, registers: &Registers) {
+    if sensor.profile_checked || sensor.expected_obis.is_empty() {
+        return;
+    }
+    sensor.profile_checked = true;
+    let missing: Vec<String> = sensor
+        .expected_obis
+        .iter()
+        .filter(|code| !registers.contains_key(code))
+        .map(|code| code.to_string())
+        .collect();
+    if missing.is_empty() {
+        info!("All OBIS codes of the meter profile present");
+    } else {
+        warn!(
+            "Meter does not report OBIS codes expected by profile: {}",
+            missing.join(", ")
+        );
     }
-    sensor.zaehlerstand_diff = (val - sensor.zaehlerstand_alt) as u32;
-    sensor.zaehlerstand_alt = val;
-    sensor.zaehlerstand = val;
+}
+
+fn update_zaehlerstand(sensor: &mut SensorData, wh: f64) {
+    if sensor.zaehlerstand_alt == 0.0 {
+        sensor.zaehlerstand_alt = wh;
+    }
+    sensor.zaehlerstand_diff = (wh - sensor.zaehlerstand_alt).max(0.0);
+    sensor.zaehlerstand_alt = wh;
+    sensor.zaehlerstand = wh;
+}
+
+/// Einspeisestatus mit Hysterese (Ein-/Ausschaltschwelle) und Haltezeit: die
+/// Schwelle muss so lange durchgehend über- bzw. unterschritten sein, bevor
+/// umgeschaltet wird, damit Wolken über der PV-Anlage kein Flattern auslösen.
+/// Liefert den neuen Status, wenn umgeschaltet wurde.
+fn update_feed_in(sensor: &mut SensorData, meter: &MeterConfig, now: Instant) -> Option<bool> {
+    let (switch, hold) = if sensor.einspeisung_sts {
+        (
+            sensor.wirkleistung > meter.feed_in_off_threshold,
+            meter.feed_in_off_hold,
+        )
+    } else {
+        (
+            sensor.wirkleistung < meter.feed_in_on_threshold,
+            meter.feed_in_on_hold,
+        )
+    };
+    if !switch {
+        sensor.feed_in_pending_since = None;
+        return None;
+    }
+    let since = *sensor.feed_in_pending_since.get_or_insert(now);
+    if now.duration_since(since) < hold {
+        return None;
+    }
+    sensor.feed_in_pending_since = None;
+    sensor.einspeisung_sts = !sensor.einspeisung_sts;
+    Some(sensor.einspeisung_sts)
 }
 
 async fn handle_logic_update(
     sensor: &mut SensorData,
-    client: &AsyncClient,
+    sinks: &mut [Box<dyn Sink>],
     app_state: &SharedAppState,
-    rrd_path: &Path,
+    meter: &MeterConfig,
 ) {
-    if sensor.wirkleistung < -500 && !sensor.einspeisung_sts {
-        sensor.einspeisung_sts = true;
-        let _ = client
-            .publish(
-                "homeassistant/binary_sensor/sml/feed/state",
-                QoS::AtLeastOnce,
-                true,
-                "ON",
-            )
-            .await;
-    } else if sensor.wirkleistung > -100 && sensor.einspeisung_sts {
-        sensor.einspeisung_sts = false;
-        let _ = client
-            .publish(
-                "homeassistant/binary_sensor/sml/feed/state",
-                QoS::AtLeastOnce,
-                true,
-                "OFF",
-            )
-            .await;
+    // Ablesezeitpunkt für die Ausgaben und die Integration der Einspeisung;
+    // bei `--replay` aus dem Mitschnitt, damit `--speed` nichts verfälscht
+    let time = sensor.capture_time.unwrap_or_else(Local::now);
+    let now = Instant::now();
+    let feed_in_changed = update_feed_in(sensor, meter, now).is_some();
+    if feed_in_changed {
+        info!(
+            "{}: Einspeisung {}",
+            meter.name,
+            if sensor.einspeisung_sts { "ON" } else { "OFF" }
+        );
     }
 
-    let now = Instant::now();
     let w = sensor.wirkleistung; //-sensor.wirkleistung;
     if let Some(last_time) = sensor.last_integration_time {
-        let dt = now.duration_since(last_time).as_millis() as u32;
-        if w < 0 || sensor.wirkleistung_alt < 0 {
-            let p_avg = (((-sensor.wirkleistung_alt).max(0) + (-w).max(0)) / 2) as u32;
-            sensor.einspeisung += p_avg as u64 * dt as u64; // 1/10 W * ms     ms 1000  3600 h    // / 360.0; // 1/10 Wh
+        // Rückwärts (Uhr verstellt, Mitschnitt erneut) wird nichts integriert
+        let dt_h = (time - last_time)
+            .to_std()
+            .unwrap_or_default()
+            .as_secs_f64()
+            / 3600.0;
+        // Nur schätzen, wenn der Zähler keine Einspeisung (2.8.0) liefert
+        if !sensor.einspeisung_vom_zaehler && (w < 0.0 || sensor.wirkleistung_alt < 0.0) {
+            // Trapezregel über die negative Leistung: W * h = Wh
+            let p_avg = ((-sensor.wirkleistung_alt).max(0.0) + (-w).max(0.0)) / 2.0;
+            sensor.einspeisung += p_avg * dt_h;
         }
     }
-    sensor.last_integration_time = Some(now);
+    sensor.last_integration_time = Some(time);
     sensor.wirkleistung_alt = sensor.wirkleistung;
 
-    update_rrd(
-        rrd_path,
-        sensor.zaehlerstand,
-        sensor.einspeisung / 2000000,
-        sensor.wirkleistung,
-    );
-
     info!(
-        "Bezug: {} Einspeisung: {} Wirkleistung: {}",
-        sensor.zaehlerstand as f64 / 10.0,
-        sensor.einspeisung as f32 / 36000000.0,
-        sensor.wirkleistung as f32 / 10.0
+        "{}: Bezug: {:.1} Wh Einspeisung: {:.1} Wh Wirkleistung: {:.1} W",
+        meter.name, sensor.zaehlerstand, sensor.einspeisung, sensor.wirkleistung
     );
 
-    let should_publish = match sensor.last_mqtt_publish {
-        Some(t) => now.duration_since(t).as_secs() > 60,
-        None => true,
+    // RRD, MQTT, ...
+    let reading = Reading {
+        meter,
+        time,
+        bezug: sensor.zaehlerstand,
+        einspeisung: sensor.einspeisung,
+        wirkleistung: sensor.wirkleistung,
+        feed_in: sensor.einspeisung_sts,
+        feed_in_changed,
+        registers: &sensor.registers,
     };
-
-    if should_publish {
-        let json_payload = format!(
-            "{{\"Time\":\"{}\",\"bezug\":{}.{},\"einspeisung\":{}.{}}}",
-            Local::now().to_rfc3339(),
-            sensor.zaehlerstand / 10,
-            sensor.zaehlerstand % 10,
-            sensor.einspeisung / 36000000,
-            sensor.einspeisung % 36000000 / 3600000
-        );
-        let _ = client
-            .publish(
-                "homeassistant/sensor/sml/zaehler/state",
-                QoS::AtLeastOnce,
-                false,
-                json_payload,
-            )
-            .await;
-        sensor.last_mqtt_publish = Some(now);
+    for sink in sinks.iter_mut() {
+        sink.reading(&reading).await;
     }
 
-    let _ = client
-        .publish(
-            "homeassistant/sensor/sml/wirkleistung/state",
-            QoS::AtLeastOnce,
-            false,
-            (sensor.wirkleistung / 10).to_string() + "." + &(sensor.wirkleistung % 10).to_string(),
-        )
-        .await;
-
     match app_state.lock() {
         Ok(mut state) => {
-            state.wirkleistung = sensor.wirkleistung as f32 / 10.0;
-            state.zaehlerstand_diff = sensor.zaehlerstand_diff as f32 / 10.0;
+            if let Some(m) = state.meter_mut(&meter.name) {
+                m.wirkleistung = sensor.wirkleistung;
+                m.zaehlerstand = sensor.zaehlerstand;
+                m.zaehlerstand_diff = sensor.zaehlerstand_diff;
+                m.einspeisung = sensor.einspeisung;
+                m.einspeisung_sts = sensor.einspeisung_sts;
+                m.registers = sensor.registers.clone();
+                m.stats = sensor.stats.clone();
+            }
             let _ = state.tx.send(SseData {
-                time: Local::now().format("%H:%M:%S").to_string(),
-                value: sensor.wirkleistung as f32 / 10.0,
-                value2: sensor.zaehlerstand_diff as f32 / 10.0,
-                total_energy: sensor.zaehlerstand as f64 / 10.0,
+                meter: meter.name.clone(),
+                time: time.format("%H:%M:%S").to_string(),
+                value: sensor.wirkleistung,
+                value2: sensor.zaehlerstand_diff,
+                total_energy: sensor.zaehlerstand,
                 is_feed_in: sensor.einspeisung_sts,
+                available: true,
+                registers: sensor.registers.clone(),
             });
         }
         Err(e) => {
@@ -376,3 +623,381 @@ async fn handle_logic_update(
         }
     }
 }
+
+/// Veröffentlicht die Frame-Statistik an die Ausgaben und im Web-API
+async fn publish_stats(
+    sensor: &mut SensorData,
+    sinks: &mut [Box<dyn Sink>],
+    app_state: &SharedAppState,
+    meter: &MeterConfig,
+) {
+    sensor.last_stats_publish = Some(Instant::now());
+    for sink in sinks.iter_mut() {
+        sink.stats(meter, &sensor.stats).await;
+    }
+    if let Ok(mut state) = app_state.lock() {
+        if let Some(m) = state.meter_mut(&meter.name) {
+            m.stats = sensor.stats.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture;
+    use crate::model::AppState;
+    use crate::profile;
+    use chrono::{DateTime, TimeDelta};
+    use clap::Parser;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+
+    /// Mitschnitt (`--record`) eines EMH eHZ mit Zweirichtungszähler:
+    /// OpenResponse, GetListResponse mit 1.8.0, 2.8.0 und 16.7.0, CloseResponse
+    const EHZ_CAPTURE: &str = "2024-05-01T12:00:00.123+02:00 \
+        1b1b1b1b01010101760500458b6c620062007265000001017601010500458b6b0b0a01454d480000b8ef\
+        2701016321c100760500458b6d6200620072650000070177010b0a01454d480000b8ef27070100620b00\
+        ff726201650c3a6b2d7577078181c78203ff0101010104454d480177070100000009ff010101010b0a01\
+        454d480000b8ef270177070100010800ff650000018201621e52ff5900000000075bcd15017707010002\
+        0800ff650000018201621e52ff59000000000000a4100177070100100700ff0101621b520055fffffea7\
+        010101638fdb00760500458b6e62006200726500000201710163a0c900001b1b1b1b1a01b899";
+
+    /// EMH eHZ EDL21 mit Zweitarif: 1.8.0, 1.8.1, 1.8.2 und 16.7.0
+    const EDL21_CAPTURE: &str = "2024-05-01T12:00:00.123+02:00 \
+        1b1b1b1b01010101760500458b6c620062007265000001017601010500458b6b0b0a01454d480000b8ef\
+        2701016321c100760500458b6d6200620072650000070177010b0a01454d480000b8ef27070100620b00\
+        ff726201650c3a6b2d7677078181c78203ff0101010104454d480177070100000009ff010101010b0a01\
+        454d480000b8ef270177070100010800ff650000018201621e52ff5900000000075bcd15017707010001\
+        0801ff0101621e52ff590000000005f5e1000177070100010802ff0101621e52ff590000000001656b15\
+        0177070100100700ff0101621b520055fffffea701010163b4fe00760500458b6e620062007265000002\
+        01710163a0c900001b1b1b1b1a01eec9";
+
+    /// ISKRA MT631: 1.8.0, 2.8.0 und 16.7.0
+    const MT631_CAPTURE: &str = "2024-05-01T12:00:00.123+02:00 \
+        1b1b1b1b010101017605001a2b3c6200620072650000010176010105001a2b3b0b0a0149534b000bc61a\
+        4e0101634795007605001a2b3d6200620072650000070177010b0a0149534b000bc61a4e070100620b00\
+        ff726201650c3a6b2d7577078181c78203ff010101010449534b0177070100000009ff010101010b0a01\
+        49534b000bc61a4e0177070100010800ff650000018201621e52ff5900000000075bcd15017707010002\
+        0800ff650000018201621e52ff59000000000000a4100177070100100700ff0101621b520055000004d2\
+        010101633c30007605001a2b3e62006200726500000201710163a34b00001b1b1b1b1a01128d";
+
+    /// EasyMeter Q3A Zweirichtungszähler, 9600 7E1
+    const EASYMETER_Q3A: &[u8] = b"/ESY5Q3AA1024 V3.03\r\n\
+        \r\n\
+        1-0:0.0.0*255(1ESY1011002413)\r\n\
+        1-0:1.8.0*255(00012345.6789*kWh)\r\n\
+        1-0:2.8.0*255(00000321.0000*kWh)\r\n\
+        1-0:1.7.255*255(-000423.51*W)\r\n\
+        1-0:96.5.5*255(82)\r\n\
+        0-0:96.1.255*255(1ESY1011002413)\r\n\
+        !\r\n";
+
+    /// Auslesung im Modus C (Landis+Gyr ZMF100) mit STX, ETX und BCC
+    const MODE_C_READOUT: &[u8] = b"/LGZ4ZMF100AC.M26\r\n\
+        \x02F.F(00)\r\n\
+        0.0.0(12345678)\r\n\
+        1.8.0(001234.567*kWh)\r\n\
+        !\r\n\
+        \x03\x21";
+
+    /// Schwellen -50 W / -10 W, Einschalten nach 30 s, Ausschalten nach 60 s
+    fn feed_in_meter() -> MeterConfig {
+        Config::try_parse_from([
+            "sml_rust",
+            "--mqtt-user",
+            "sml",
+            "--mqtt-pass",
+            "sml",
+            "--feed-in-on-threshold",
+            "-50",
+            "--feed-in-off-threshold",
+            "-10",
+            "--feed-in-on-hold",
+            "30s",
+            "--feed-in-off-hold",
+            "60s",
+        ])
+        .unwrap()
+        .meters()
+        .unwrap()
+        .remove(0)
+    }
+
+    fn feed_in(sensor: &mut SensorData, meter: &MeterConfig, w: f64, at: Instant) -> Option<bool> {
+        sensor.wirkleistung = w;
+        update_feed_in(sensor, meter, at)
+    }
+
+    fn app_state() -> SharedAppState {
+        Arc::new(Mutex::new(AppState {
+            meters: Vec::new(),
+            tx: broadcast::channel(16).0,
+            mqtt_connected: None,
+        }))
+    }
+
+    fn capture_bytes(line: &str) -> Vec<u8> {
+        capture::parse_line(line).unwrap().unwrap().bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Vec<Result<Option<Vec<u8>>, sml_rs::transport::DecodeErr>> {
+        let mut decoder = sml_rs::transport::Decoder::<Vec<u8>>::new();
+        bytes
+            .iter()
+            .map(|&b| decoder.push_byte(b).map(|frame| frame.map(<[u8]>::to_vec)))
+            .collect()
+    }
+
+    #[test]
+    fn sml_registers_from_capture() {
+        let bytes = capture_bytes(EHZ_CAPTURE);
+        let frame = decode(&bytes).into_iter().find_map(|r| r.unwrap()).unwrap();
+        let registers = sml_registers(sml_rs::parser::complete::parse(&frame).unwrap());
+
+        assert_eq!(registers.len(), 5);
+        assert_eq!(
+            registers[&obis::HERSTELLER].value,
+            RegisterValue::Bytes(b"EMH".to_vec())
+        );
+        let bezug = &registers[&obis::ZAEHLERSTAND];
+        assert_eq!(bezug.value, RegisterValue::Int(123456789));
+        assert_eq!(bezug.scaler, Some(-1));
+        assert_eq!(bezug.unit_name(), Some("Wh"));
+        assert_eq!(bezug.status, Some(0x182));
+        assert!((bezug.scaled().unwrap() - 12345678.9).abs() < 1e-6);
+        assert!((registers[&obis::EINSPEISUNG].scaled().unwrap() - 4200.0).abs() < 1e-9);
+        assert_eq!(registers[&obis::WIRKLEISTUNG].scaled(), Some(-345.0));
+    }
+
+    /// Merkt sich den Ablesezeitpunkt jedes Messwerts
+    struct TimeSink(Arc<Mutex<Vec<DateTime<Local>>>>);
+
+    #[async_trait::async_trait]
+    impl Sink for TimeSink {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        async fn reading(&mut self, reading: &Reading<'_>) {
+            self.0.lock().unwrap().push(reading.time);
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_uses_capture_time() {
+        let meter = Config::try_parse_from(["sml_rust"])
+            .unwrap()
+            .meters()
+            .unwrap()
+            .remove(0);
+        let times = Arc::new(Mutex::new(Vec::new()));
+        let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(TimeSink(times.clone()))];
+        let mut decoder = FrameDecoder::new(meter.protocol);
+        let mut sensor = SensorData::new();
+        let frame = capture::parse_line(EDL21_CAPTURE).unwrap().unwrap();
+        let capture_times = [frame.time, frame.time + TimeDelta::hours(1)];
+
+        // Ohne 2.8.0 wird die Einspeisung über die Aufnahmezeitpunkte integriert,
+        // unabhängig davon, wie schnell der Mitschnitt abgespielt wird
+        for time in capture_times {
+            sensor.capture_time = Some(time.with_timezone(&Local));
+            process_bytes(
+                &frame.bytes,
+                &mut decoder,
+                &mut None,
+                &mut sensor,
+                &mut sinks,
+                &app_state(),
+                &meter,
+            )
+            .await;
+        }
+
+        assert_eq!(*times.lock().unwrap(), capture_times);
+        // -345 W über eine Stunde
+        assert!((sensor.einspeisung - 345.0).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn d0_telegram_sets_power() {
+        let meter = Config::try_parse_from(["sml_rust", "--protocol", "d0"])
+            .unwrap()
+            .meters()
+            .unwrap()
+            .remove(0);
+        let mut decoder = FrameDecoder::new(meter.protocol);
+        let mut sensor = SensorData::new();
+        process_bytes(
+            d0::tests::EASYMETER_Q3D,
+            &mut decoder,
+            &mut None,
+            &mut sensor,
+            &mut [],
+            &app_state(),
+            &meter,
+        )
+        .await;
+
+        assert_eq!(sensor.stats.valid_readings, 1);
+        assert!((sensor.zaehlerstand - 527032.6214).abs() < 1e-6);
+        // 1-0:1.7.255 statt 16.7.0
+        assert!((sensor.wirkleistung - 135.89).abs() < 1e-9);
+        // Bezug über der Ausschaltschwelle beendet die Einspeisung
+        assert!(!sensor.einspeisung_sts);
+    }
+
+    #[tokio::test]
+    async fn profile_obis_in_sample_telegrams() {
+        let samples = [
+            ("generic-sml", capture_bytes(EHZ_CAPTURE)),
+            ("ehz-edl21", capture_bytes(EDL21_CAPTURE)),
+            ("iskra-mt631", capture_bytes(MT631_CAPTURE)),
+            ("easymeter-q3a", EASYMETER_Q3A.to_vec()),
+            ("easymeter-q3d", d0::tests::EASYMETER_Q3D.to_vec()),
+            ("iec-62056-21", MODE_C_READOUT.to_vec()),
+        ];
+        assert_eq!(samples.len(), profile::PROFILES.len());
+
+        for (name, bytes) in samples {
+            let meter = Config::try_parse_from(["sml_rust", "--meter-profile", name])
+                .unwrap()
+                .meters()
+                .unwrap()
+                .remove(0);
+            let mut decoder = FrameDecoder::new(meter.protocol);
+            let mut sensor = SensorData::new();
+            process_bytes(
+                &bytes,
+                &mut decoder,
+                &mut None,
+                &mut sensor,
+                &mut [],
+                &app_state(),
+                &meter,
+            )
+            .await;
+
+            assert_eq!(sensor.stats.valid_readings, 1, "{}", name);
+            for code in profile::find(name).unwrap().obis {
+                assert!(
+                    sensor.registers.contains_key(code),
+                    "{}: {} missing",
+                    name,
+                    code
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn registers_merged_until_expiry() {
+        let register = |value| Register {
+            value: RegisterValue::Int(value),
+            scaler: Some(0),
+            unit: Some(27),
+            status: None,
+        };
+        let mut sensor = SensorData::new();
+        let start = Instant::now();
+        merge_registers(
+            &mut sensor,
+            Registers::from([
+                (obis::ZAEHLERSTAND, register(1)),
+                (obis::LEISTUNG_L1, register(2)),
+            ]),
+            start,
+        );
+
+        // Telegramm ohne Leistung an L1: der letzte Wert bleibt erhalten
+        let next = Registers::from([(obis::ZAEHLERSTAND, register(3))]);
+        merge_registers(&mut sensor, next.clone(), start + Duration::from_secs(10));
+        assert_eq!(sensor.registers.len(), 2);
+        assert_eq!(sensor.registers[&obis::ZAEHLERSTAND], register(3));
+        assert_eq!(sensor.registers[&obis::LEISTUNG_L1], register(2));
+
+        merge_registers(&mut sensor, next, start + REGISTER_EXPIRY);
+        assert_eq!(sensor.registers.len(), 1);
+        assert!(!sensor.registers_seen.contains_key(&obis::LEISTUNG_L1));
+    }
+
+    #[test]
+    fn sml_checksum_mismatch() {
+        let mut bytes = capture_bytes(EHZ_CAPTURE);
+        // Leistung -345 W → -344 W, Prüfsumme passt nicht mehr
+        let pos = bytes
+            .windows(4)
+            .position(|w| w == [0xff, 0xff, 0xfe, 0xa7])
+            .unwrap();
+        bytes[pos + 3] = 0xa8;
+        let results = decode(&bytes);
+        assert!(results.iter().all(|r| !matches!(r, Ok(Some(_)))));
+        assert!(results.iter().any(|r| matches!(
+            r,
+            Err(sml_rs::transport::DecodeErr::InvalidMessage {
+                checksum_mismatch: (got, expected),
+                ..
+            }) if got != expected
+        )));
+    }
+
+    #[test]
+    fn feed_in_hysteresis() {
+        let mut meter = feed_in_meter();
+        meter.feed_in_on_hold = Duration::ZERO;
+        meter.feed_in_off_hold = Duration::ZERO;
+        let mut sensor = SensorData::new();
+        let t = Instant::now();
+        sensor.einspeisung_sts = false;
+
+        // Zwischen den Schwellen bleibt der Status erhalten
+        assert_eq!(feed_in(&mut sensor, &meter, -30.0, t), None);
+        assert_eq!(feed_in(&mut sensor, &meter, -60.0, t), Some(true));
+        assert_eq!(feed_in(&mut sensor, &meter, -30.0, t), None);
+        assert_eq!(feed_in(&mut sensor, &meter, -10.0, t), None);
+        assert_eq!(feed_in(&mut sensor, &meter, 5.0, t), Some(false));
+        assert!(!sensor.einspeisung_sts);
+    }
+
+    #[test]
+    fn feed_in_hold_times() {
+        let meter = feed_in_meter();
+        let mut sensor = SensorData::new();
+        let t = Instant::now();
+        sensor.einspeisung_sts = false;
+
+        assert_eq!(feed_in(&mut sensor, &meter, -200.0, t), None);
+        assert_eq!(sensor.feed_in_pending_since, Some(t));
+        assert_eq!(
+            feed_in(&mut sensor, &meter, -200.0, t + Duration::from_secs(29)),
+            None
+        );
+        assert_eq!(
+            feed_in(&mut sensor, &meter, -200.0, t + Duration::from_secs(30)),
+            Some(true)
+        );
+        assert_eq!(sensor.feed_in_pending_since, None);
+
+        // Wolke: kurz über der Ausschaltschwelle setzt die Wartezeit zurück
+        let t = t + Duration::from_secs(100);
+        assert_eq!(feed_in(&mut sensor, &meter, 300.0, t), None);
+        assert_eq!(
+            feed_in(&mut sensor, &meter, -80.0, t + Duration::from_secs(40)),
+            None
+        );
+        assert_eq!(sensor.feed_in_pending_since, None);
+        assert_eq!(
+            feed_in(&mut sensor, &meter, 300.0, t + Duration::from_secs(50)),
+            None
+        );
+        assert_eq!(
+            feed_in(&mut sensor, &meter, 300.0, t + Duration::from_secs(109)),
+            None
+        );
+        assert_eq!(
+            feed_in(&mut sensor, &meter, 300.0, t + Duration::from_secs(110)),
+            Some(false)
+        );
+    }
+}
@@ -0,0 +1,438 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, FixedOffset};
+use serialport::{Parity, SerialPort, StopBits};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader, Lines};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::warn;
+
+use crate::capture;
+use crate::config::Input;
+use crate::d0;
+use crate::profile::{Protocol, SerialSettings};
+
+const CHUNK_SIZE: usize = 256;
+
+/// Quelle für rohe SML-Bytes. Das Dekodieren und Verarbeiten übernimmt
+/// `sml::run_input_loop`, unabhängig davon, woher die Daten kommen.
+#[async_trait]
+pub trait SmlSource: Send {
+    /// Bezeichnung für Logausgaben
+    fn describe(&self) -> String;
+
+    /// Öffnet bzw. verbindet die Quelle (wird nach Fehlern erneut aufgerufen)
+    async fn open(&mut self) -> io::Result<()>;
+
+    /// Liest den nächsten Block Rohdaten. `Ok(None)` bedeutet Ende des Datenstroms.
+    /// Muss abbruchsicher sein: die Leseschleife wartet per `select!` auch auf
+    /// Timer, ein abgebrochener Aufruf darf keine Daten verlieren.
+    async fn read(&mut self) -> io::Result<Option<Bytes>>;
+
+    /// Soll nach einem Fehler oder Ende des Datenstroms neu geöffnet werden?
+    /// Endliche Quellen (Datei, stdin) beenden stattdessen die Leseschleife.
+    fn restartable(&self) -> bool {
+        true
+    }
+
+    /// Aufnahmezeitpunkt der zuletzt gelesenen Daten bei Mitschnitten mit
+    /// Zeitstempeln (`--replay`); sonst gilt die Empfangszeit
+    fn capture_time(&self) -> Option<DateTime<FixedOffset>> {
+        None
+    }
+}
+
+/// `read_timeout` (`--read-timeout`): so lange darf ein serieller Port bzw. eine
+/// TCP-Verbindung ohne Daten bleiben, bevor sie neu geöffnet wird
+pub fn from_input(
+    input: &Input,
+    serial: SerialSettings,
+    protocol: Protocol,
+    read_timeout: Duration,
+) -> Box<dyn SmlSource> {
+    let mode_c = matches!(protocol, Protocol::D0 { mode_c: true });
+    if mode_c && !matches!(input, Input::Serial(_)) {
+        warn!(
+            "D0 mode C requires a serial port, ignoring it for {:?}",
+            input
+        );
+    }
+    match input {
+        Input::Serial(path) => Box::new(SerialSource::new(path, serial, mode_c, read_timeout)),
+        Input::Tcp(addr) => Box::new(TcpSource::new(addr, read_timeout)),
+        Input::File(path) => Box::new(FileSource::new(path.clone())),
+        Input::Stdin => Box::new(StdinSource::new()),
+        Input::Replay { path, speed } => Box::new(ReplaySource::new(path.clone(), *speed)),
+    }
+}
+
+// --- Serieller Port (IR-Lesekopf) ---
+
+/// Der blockierende Lesezugriff läuft in einem eigenen Thread und liefert die
+/// Daten über einen Channel, damit kein tokio-Worker blockiert wird.
+pub struct SerialSource {
+    path: String,
+    settings: SerialSettings,
+    mode_c: bool,
+    read_timeout: Duration,
+    rx: Option<mpsc::Receiver<io::Result<Bytes>>>,
+}
+
+impl SerialSource {
+    pub fn new(path: &str, settings: SerialSettings, mode_c: bool, read_timeout: Duration) -> Self {
+        Self {
+            path: path.to_string(),
+            settings,
+            mode_c,
+            read_timeout,
+            rx: None,
+        }
+    }
+}
+
+#[async_trait]
+impl SmlSource for SerialSource {
+    fn describe(&self) -> String {
+        let s = &self.settings;
+        let parity = match s.parity {
+            Parity::None => 'N',
+            Parity::Even => 'E',
+            Parity::Odd => 'O',
+        };
+        let stop_bits = match s.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        format!(
+            "{} ({} {}{}{})",
+            self.path,
+            s.baud_rate,
+            u8::from(s.data_bits),
+            parity,
+            stop_bits
+        )
+    }
+
+    async fn open(&mut self) -> io::Result<()> {
+        // Ein noch laufender Lesethread beendet sich, sobald er den Receiver verliert
+        self.rx = None;
+        let path = self.path.clone();
+        let settings = self.settings;
+        let read_timeout = self.read_timeout;
+        let port = tokio::task::spawn_blocking(move || {
+            serialport::new(&path, settings.baud_rate)
+                .data_bits(settings.data_bits)
+                .parity(settings.parity)
+                .stop_bits(settings.stop_bits)
+                .flow_control(settings.flow_control)
+                .timeout(read_timeout)
+                .open()
+        })
+        .await
+        .map_err(io::Error::other)??;
+
+        let (tx, rx) = mpsc::channel(SERIAL_CHANNEL_SIZE);
+        let builder = std::thread::Builder::new().name(format!("serial {}", self.path));
+        if self.mode_c {
+            builder.spawn(move || mode_c_reader(port, settings.baud_rate, tx))?;
+        } else {
+            builder.spawn(move || serial_reader(port, tx))?;
+        }
+        self.rx = Some(rx);
+        Ok(())
+    }
+
+    async fn read(&mut self) -> io::Result<Option<Bytes>> {
+        let rx = match self.rx.as_mut() {
+            Some(rx) => rx,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "port not open")),
+        };
+        match rx.recv().await {
+            Some(result) => result.map(Some),
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "serial reader thread stopped",
+            )),
+        }
+    }
+}
+
+const SERIAL_CHANNEL_SIZE: usize = 32;
+
+/// Lesethread für den seriellen Port. Endet bei einem Lesefehler (inkl. Timeout)
+/// oder wenn die Gegenseite den Channel geschlossen hat.
+fn serial_reader(mut port: Box<dyn SerialPort>, tx: mpsc::Sender<io::Result<Bytes>>) {
+    let mut buf = [0u8; CHUNK_SIZE];
+    loop {
+        let result = match port.read(&mut buf) {
+            Ok(0) => {
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Ok(n) => Ok(Bytes::copy_from_slice(&buf[..n])),
+            Err(e) => Err(e),
+        };
+        let failed = result.is_err();
+        if tx.blocking_send(result).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Lesethread für D0-Zähler im Modus C: fragt den Zähler zyklisch ab.
+fn mode_c_reader(
+    mut port: Box<dyn SerialPort>,
+    initial_baud: u32,
+    tx: mpsc::Sender<io::Result<Bytes>>,
+) {
+    loop {
+        let result = d0::mode_c_readout(port.as_mut(), initial_baud).map(Bytes::from);
+        let failed = result.is_err();
+        if tx.blocking_send(result).is_err() || failed {
+            return;
+        }
+        std::thread::sleep(d0::MODE_C_POLL_INTERVAL);
+    }
+}
+
+// --- TCP-Client (ser2net, Tasmota TCP-Bridge) ---
+
+pub struct TcpSource {
+    addr: String,
+    read_timeout: Duration,
+    stream: Option<TcpStream>,
+    buf: [u8; CHUNK_SIZE],
+}
+
+impl TcpSource {
+    pub fn new(addr: &str, read_timeout: Duration) -> Self {
+        Self {
+            addr: addr.to_string(),
+            read_timeout,
+            stream: None,
+            buf: [0u8; CHUNK_SIZE],
+        }
+    }
+}
+
+#[async_trait]
+impl SmlSource for TcpSource {
+    fn describe(&self) -> String {
+        format!("tcp://{}", self.addr)
+    }
+
+    async fn open(&mut self) -> io::Result<()> {
+        self.stream = None;
+        let stream = tokio::time::timeout(self.read_timeout, TcpStream::connect(&self.addr))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    async fn read(&mut self) -> io::Result<Option<Bytes>> {
+        let stream = match self.stream.as_mut() {
+            Some(s) => s,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "not connected")),
+        };
+        read_chunk(stream, &mut self.buf, self.read_timeout).await
+    }
+}
+
+// --- Mitschnitt-Datei (rohe Bytes, z.B. `cat /dev/ttyUSB0 > dump.bin`) ---
+
+pub struct FileSource {
+    path: PathBuf,
+    file: Option<tokio::fs::File>,
+    buf: [u8; FILE_CHUNK_SIZE],
+}
+
+/// 96 Bytes alle 100 ms entsprechen in etwa der Datenrate einer 9600-Baud-Leitung,
+/// damit RRD und MQTT nicht mit Updates in derselben Sekunde überflutet werden.
+const FILE_CHUNK_SIZE: usize = 96;
+const FILE_CHUNK_DELAY: Duration = Duration::from_millis(100);
+
+impl FileSource {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: None,
+            buf: [0u8; FILE_CHUNK_SIZE],
+        }
+    }
+}
+
+#[async_trait]
+impl SmlSource for FileSource {
+    fn describe(&self) -> String {
+        format!("file://{}", self.path.display())
+    }
+
+    async fn open(&mut self) -> io::Result<()> {
+        self.file = Some(tokio::fs::File::open(&self.path).await?);
+        Ok(())
+    }
+
+    async fn read(&mut self) -> io::Result<Option<Bytes>> {
+        let file = match self.file.as_mut() {
+            Some(f) => f,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "file not open")),
+        };
+        sleep(FILE_CHUNK_DELAY).await;
+        let n = file.read(&mut self.buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Bytes::copy_from_slice(&self.buf[..n])))
+    }
+
+    fn restartable(&self) -> bool {
+        false
+    }
+}
+
+// --- Standardeingabe (z.B. `socat tcp:host:port - | sml_rust --input stdin`) ---
+
+pub struct StdinSource {
+    stdin: tokio::io::Stdin,
+    buf: [u8; CHUNK_SIZE],
+}
+
+impl StdinSource {
+    pub fn new() -> Self {
+        Self {
+            stdin: tokio::io::stdin(),
+            buf: [0u8; CHUNK_SIZE],
+        }
+    }
+}
+
+#[async_trait]
+impl SmlSource for StdinSource {
+    fn describe(&self) -> String {
+        "stdin".to_string()
+    }
+
+    async fn open(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn read(&mut self) -> io::Result<Option<Bytes>> {
+        let n = self.stdin.read(&mut self.buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Bytes::copy_from_slice(&self.buf[..n])))
+    }
+
+    fn restartable(&self) -> bool {
+        false
+    }
+}
+
+// --- Wiedergabe eines mit `--record` erstellten Mitschnitts ---
+
+pub struct ReplaySource {
+    path: PathBuf,
+    speed: f64,
+    lines: Option<Lines<BufReader<tokio::fs::File>>>,
+    last_time: Option<DateTime<FixedOffset>>,
+    /// Gelesener Frame, der erst zu diesem Zeitpunkt ausgeliefert wird
+    pending: Option<(Bytes, Instant)>,
+}
+
+impl ReplaySource {
+    pub fn new(path: PathBuf, speed: f64) -> Self {
+        Self {
+            path,
+            speed,
+            lines: None,
+            last_time: None,
+            pending: None,
+        }
+    }
+
+    async fn next_frame(&mut self) -> io::Result<Option<capture::Frame>> {
+        let lines = match self.lines.as_mut() {
+            Some(l) => l,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, "file not open")),
+        };
+        // `next_line` ist abbruchsicher
+        while let Some(line) = lines.next_line().await? {
+            match capture::parse_line(&line) {
+                Some(Ok(frame)) => return Ok(Some(frame)),
+                Some(Err(e)) => warn!("Skipping capture line: {}", e),
+                None => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl SmlSource for ReplaySource {
+    fn describe(&self) -> String {
+        format!("replay://{} ({}x)", self.path.display(), self.speed)
+    }
+
+    async fn open(&mut self) -> io::Result<()> {
+        let file = tokio::fs::File::open(&self.path).await?;
+        self.lines = Some(BufReader::new(file).lines());
+        self.last_time = None;
+        self.pending = None;
+        Ok(())
+    }
+
+    async fn read(&mut self) -> io::Result<Option<Bytes>> {
+        // Erst den Frame merken, dann warten: ein Abbruch während der Pause
+        // verliert ihn nicht, der nächste Aufruf wartet bis zum selben Zeitpunkt
+        if self.pending.is_none() {
+            let Some(frame) = self.next_frame().await? else {
+                return Ok(None);
+            };
+            // Originale Abstände zwischen den Frames, skaliert mit `--speed`
+            let gap = self
+                .last_time
+                .map(|last| (frame.time - last).to_std().unwrap_or_default())
+                .unwrap_or_default();
+            self.last_time = Some(frame.time);
+            self.pending = Some((
+                Bytes::from(frame.bytes),
+                Instant::now() + gap.div_f64(self.speed),
+            ));
+        }
+        if let Some((_, due)) = &self.pending {
+            sleep_until(*due).await;
+        }
+        Ok(self.pending.take().map(|(bytes, _)| bytes))
+    }
+
+    fn restartable(&self) -> bool {
+        false
+    }
+
+    fn capture_time(&self) -> Option<DateTime<FixedOffset>> {
+        self.last_time
+    }
+}
+
+/// Liest einen Block mit Timeout, damit eine stumme Gegenstelle erkannt wird.
+async fn read_chunk<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> io::Result<Option<Bytes>> {
+    match tokio::time::timeout(timeout, reader.read(buf)).await {
+        Ok(Ok(0)) => Ok(None),
+        Ok(Ok(n)) => Ok(Some(Bytes::copy_from_slice(&buf[..n]))),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no data for {:?}", timeout),
+        )),
+    }
+}
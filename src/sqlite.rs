@@ -0,0 +1,518 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveTime, TimeDelta, TimeZone, Timelike};
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::sink::{Reading, Sink};
+
+/// Gepufferte Messwerte aller Zähler, bis der Writer sie übernimmt
+const CHANNEL_SIZE: usize = 10000;
+/// Höchstens so viele Messwerte je Transaktion
+const BATCH_SIZE: usize = 500;
+/// Höchstens so viele Punkte je Anfrage an `/api/meters/{name}/history`
+const MAX_POINTS: usize = 100000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS readings (
+    meter TEXT NOT NULL,
+    ts INTEGER NOT NULL,                -- Unix-Zeit in ms
+    bezug REAL NOT NULL,                -- Wh
+    einspeisung REAL NOT NULL,          -- Wh
+    wirkleistung REAL NOT NULL,         -- W
+    feed_in INTEGER NOT NULL,
+    PRIMARY KEY (meter, ts)
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS aggregates (
+    meter TEXT NOT NULL,
+    resolution TEXT NOT NULL,           -- 'hour' oder 'day'
+    start INTEGER NOT NULL,             -- Beginn in lokaler Zeit, Unix-Zeit in s
+    samples INTEGER NOT NULL,
+    wirkleistung_sum REAL NOT NULL,
+    wirkleistung_min REAL NOT NULL,
+    wirkleistung_max REAL NOT NULL,
+    bezug_first REAL NOT NULL,
+    bezug_last REAL NOT NULL,
+    einspeisung_first REAL NOT NULL,
+    einspeisung_last REAL NOT NULL,
+    PRIMARY KEY (meter, resolution, start)
+) WITHOUT ROWID;
+";
+
+const INSERT_READING: &str = "
+INSERT OR IGNORE INTO readings (meter, ts, bezug, einspeisung, wirkleistung, feed_in)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+
+const UPSERT_AGGREGATE: &str = "
+INSERT INTO aggregates (meter, resolution, start, samples,
+    wirkleistung_sum, wirkleistung_min, wirkleistung_max,
+    bezug_first, bezug_last, einspeisung_first, einspeisung_last)
+VALUES (?1, ?2, ?3, 1, ?4, ?4, ?4, ?5, ?5, ?6, ?6)
+ON CONFLICT (meter, resolution, start) DO UPDATE SET
+    samples = samples + 1,
+    wirkleistung_sum = wirkleistung_sum + excluded.wirkleistung_sum,
+    wirkleistung_min = min(wirkleistung_min, excluded.wirkleistung_min),
+    wirkleistung_max = max(wirkleistung_max, excluded.wirkleistung_max),
+    bezug_last = excluded.bezug_last,
+    einspeisung_last = excluded.einspeisung_last";
+
+/// Ein Messwert auf dem Weg zum Writer
+pub struct Row {
+    meter: String,
+    time: DateTime<Local>,
+    bezug: f64,
+    einspeisung: f64,
+    wirkleistung: f64,
+    feed_in: bool,
+}
+
+/// Speichert jeden Messwert in SQLite. Die Stunden- und Tageswerte
+/// aktualisiert der Writer in derselben Transaktion.
+pub struct SqliteSink {
+    tx: mpsc::Sender<Row>,
+}
+
+impl SqliteSink {
+    pub fn new(tx: mpsc::Sender<Row>) -> Self {
+        Self { tx }
+    }
+}
+
+#[async_trait]
+impl Sink for SqliteSink {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    async fn reading(&mut self, reading: &Reading<'_>) {
+        let row = Row {
+            meter: reading.meter.name.clone(),
+            time: reading.time,
+            bezug: reading.bezug,
+            einspeisung: reading.einspeisung,
+            wirkleistung: reading.wirkleistung,
+            feed_in: reading.feed_in,
+        };
+        if self.tx.try_send(row).is_err() {
+            warn!("SQLite writer is not keeping up, dropping reading");
+        }
+    }
+}
+
+/// Öffnet die Datenbank, legt die Tabellen an und startet den Writer in
+/// einem eigenen Thread (rusqlite blockiert). Er endet, sobald alle
+/// `SqliteSink`s verworfen sind.
+pub fn start(path: &Path) -> anyhow::Result<(mpsc::Sender<Row>, JoinHandle<()>)> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let conn = Connection::open(path)?;
+    // WAL: die Web-API liest, während der Writer schreibt
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    conn.execute_batch(SCHEMA)?;
+    info!("SQLite database {}", path.display());
+
+    let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+    let handle = std::thread::Builder::new()
+        .name("sqlite".to_string())
+        .spawn(move || run_writer(conn, rx))?;
+    Ok((tx, handle))
+}
+
+fn run_writer(mut conn: Connection, mut rx: mpsc::Receiver<Row>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while let Some(row) = rx.blocking_recv() {
+        batch.push(row);
+        while batch.len() < BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(row) => batch.push(row),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = write_batch(&mut conn, &batch) {
+            error!("SQLite write failed, {} readings lost: {}", batch.len(), e);
+        }
+        batch.clear();
+    }
+    info!("SQLite writer stopped");
+}
+
+fn write_batch(conn: &mut Connection, rows: &[Row]) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut insert = tx.prepare_cached(INSERT_READING)?;
+        let mut aggregate = tx.prepare_cached(UPSERT_AGGREGATE)?;
+        for row in rows {
+            let inserted = insert.execute(params![
+                row.meter,
+                row.time.timestamp_millis(),
+                row.bezug,
+                row.einspeisung,
+                row.wirkleistung,
+                row.feed_in
+            ])?;
+            // Zeitstempel schon vorhanden: nicht doppelt in die Aggregate
+            if inserted == 0 {
+                continue;
+            }
+            for resolution in [Resolution::Hour, Resolution::Day] {
+                aggregate.execute(params![
+                    row.meter,
+                    resolution.as_str(),
+                    resolution.start(row.time),
+                    row.wirkleistung,
+                    row.bezug,
+                    row.einspeisung
+                ])?;
+            }
+        }
+    }
+    tx.commit()
+}
+
+/// Auflösung einer Abfrage: einzelne Messwerte oder Stunden-/Tageswerte
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    #[default]
+    Raw,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    fn as_str(self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Hour => "hour",
+            Resolution::Day => "day",
+        }
+    }
+
+    /// Zeitraum einer Abfrage ohne `from`
+    pub fn default_range(self) -> TimeDelta {
+        match self {
+            Resolution::Raw => TimeDelta::hours(1),
+            Resolution::Hour => TimeDelta::days(7),
+            Resolution::Day => TimeDelta::days(366),
+        }
+    }
+
+    /// Beginn der Stunde bzw. des Tages in lokaler Zeit als Unix-Zeit (s)
+    fn start(self, time: DateTime<Local>) -> i64 {
+        let hour = time.timestamp() - i64::from(time.minute() * 60 + time.second());
+        match self {
+            Resolution::Raw => time.timestamp(),
+            Resolution::Hour => hour,
+            // Mitternacht gibt es nicht in jeder Zeitzone an jedem Tag
+            Resolution::Day => Local
+                .from_local_datetime(&time.date_naive().and_time(NaiveTime::MIN))
+                .earliest()
+                .map_or(hour, |t| t.timestamp()),
+        }
+    }
+}
+
+/// Einzelner Messwert aus `readings`
+#[derive(Serialize)]
+pub struct RawPoint {
+    time: String,
+    bezug: f64,
+    einspeisung: f64,
+    wirkleistung: f64,
+    feed_in: bool,
+}
+
+/// Stunden- bzw. Tageswert aus `aggregates`
+#[derive(Serialize)]
+pub struct AggregatePoint {
+    start: String,
+    samples: i64,
+    wirkleistung_avg: f64,
+    wirkleistung_min: f64,
+    wirkleistung_max: f64,
+    /// Zählerstände (Wh) beim ersten und letzten Messwert im Zeitraum
+    bezug_first: f64,
+    bezug_last: f64,
+    einspeisung_first: f64,
+    einspeisung_last: f64,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum HistoryPoints {
+    Raw(Vec<RawPoint>),
+    Aggregated(Vec<AggregatePoint>),
+}
+
+/// Lesezugriff für die Web-API, je Anfrage eine eigene Verbindung
+#[derive(Clone)]
+pub struct History {
+    path: PathBuf,
+    meters: Vec<String>,
+}
+
+impl History {
+    pub fn new(path: PathBuf, meters: Vec<String>) -> Self {
+        Self { path, meters }
+    }
+
+    pub fn has_meter(&self, name: &str) -> bool {
+        self.meters.iter().any(|m| m == name)
+    }
+
+    /// Messwerte im Zeitraum `[from, to)`, höchstens `MAX_POINTS`. Blockiert.
+    pub fn query(
+        &self,
+        meter: &str,
+        resolution: Resolution,
+        from: DateTime<Local>,
+        to: DateTime<Local>,
+    ) -> rusqlite::Result<HistoryPoints> {
+        let conn = Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        query_points(&conn, meter, resolution, from, to)
+    }
+}
+
+/// `History::query` auf einer offenen Verbindung
+fn query_points(
+    conn: &Connection,
+    meter: &str,
+    resolution: Resolution,
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+) -> rusqlite::Result<HistoryPoints> {
+    let limit = MAX_POINTS as i64;
+    match resolution {
+        Resolution::Raw => {
+            let mut stmt = conn.prepare(
+                "SELECT ts, bezug, einspeisung, wirkleistung, feed_in FROM readings
+                 WHERE meter = ?1 AND ts >= ?2 AND ts < ?3 ORDER BY ts LIMIT ?4",
+            )?;
+            let points = stmt
+                .query_map(
+                    params![meter, from.timestamp_millis(), to.timestamp_millis(), limit],
+                    |row| {
+                        Ok(RawPoint {
+                            time: format_time(Local.timestamp_millis_opt(row.get(0)?)),
+                            bezug: row.get(1)?,
+                            einspeisung: row.get(2)?,
+                            wirkleistung: row.get(3)?,
+                            feed_in: row.get(4)?,
+                        })
+                    },
+                )?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(HistoryPoints::Raw(points))
+        }
+        Resolution::Hour | Resolution::Day => {
+            let mut stmt = conn.prepare(
+                "SELECT start, samples, wirkleistung_sum, wirkleistung_min, wirkleistung_max,
+                        bezug_first, bezug_last, einspeisung_first, einspeisung_last
+                 FROM aggregates
+                 WHERE meter = ?1 AND resolution = ?2 AND start >= ?3 AND start < ?4
+                 ORDER BY start LIMIT ?5",
+            )?;
+            let points = stmt
+                .query_map(
+                    params![
+                        meter,
+                        resolution.as_str(),
+                        resolution.start(from),
+                        to.timestamp(),
+                        limit
+                    ],
+                    |row| {
+                        let samples: i64 = row.get(1)?;
+                        let sum: f64 = row.get(2)?;
+                        Ok(AggregatePoint {
+                            start: format_time(Local.timestamp_opt(row.get(0)?, 0)),
+                            samples,
+                            wirkleistung_avg: sum / samples.max(1) as f64,
+                            wirkleistung_min: row.get(3)?,
+                            wirkleistung_max: row.get(4)?,
+                            bezug_first: row.get(5)?,
+                            bezug_last: row.get(6)?,
+                            einspeisung_first: row.get(7)?,
+                            einspeisung_last: row.get(8)?,
+                        })
+                    },
+                )?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(HistoryPoints::Aggregated(points))
+        }
+    }
+}
+
+fn format_time(time: chrono::LocalResult<DateTime<Local>>) -> String {
+    time.earliest().map(|t| t.to_rfc3339()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        conn
+    }
+
+    /// 15.01.2024, `hour`:`minute` Ortszeit (Winterzeit, eindeutig)
+    fn at(hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 1, 15, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn row(
+        meter: &str,
+        time: DateTime<Local>,
+        bezug: f64,
+        einspeisung: f64,
+        wirkleistung: f64,
+    ) -> Row {
+        Row {
+            meter: meter.to_string(),
+            time,
+            bezug,
+            einspeisung,
+            wirkleistung,
+            feed_in: wirkleistung < 0.0,
+        }
+    }
+
+    fn aggregated(points: HistoryPoints) -> Vec<AggregatePoint> {
+        match points {
+            HistoryPoints::Aggregated(points) => points,
+            HistoryPoints::Raw(_) => panic!("raw points"),
+        }
+    }
+
+    fn raw(points: HistoryPoints) -> Vec<RawPoint> {
+        match points {
+            HistoryPoints::Raw(points) => points,
+            HistoryPoints::Aggregated(_) => panic!("aggregated points"),
+        }
+    }
+
+    #[test]
+    fn schema_can_be_applied_again() {
+        let conn = database();
+        conn.execute_batch(SCHEMA).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap();
+        let tables: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(tables, ["aggregates", "readings"]);
+    }
+
+    #[test]
+    fn hourly_and_daily_aggregates() {
+        let mut conn = database();
+        write_batch(
+            &mut conn,
+            &[
+                row("haus", at(10, 0), 1000.0, 5.0, 100.0),
+                row("haus", at(10, 30), 1010.0, 5.0, 300.0),
+                row("haus", at(11, 15), 1030.0, 8.0, -200.0),
+                row("wp", at(10, 10), 50.0, 0.0, 2000.0),
+            ],
+        )
+        .unwrap();
+        // Derselbe Zeitstempel noch einmal zählt nicht doppelt
+        write_batch(&mut conn, &[row("haus", at(10, 30), 1010.0, 5.0, 300.0)]).unwrap();
+
+        let hours = aggregated(
+            query_points(&conn, "haus", Resolution::Hour, at(10, 0), at(12, 0)).unwrap(),
+        );
+        assert_eq!(hours.len(), 2);
+        assert_eq!(hours[0].start, at(10, 0).to_rfc3339());
+        assert_eq!(hours[0].samples, 2);
+        assert!((hours[0].wirkleistung_avg - 200.0).abs() < 1e-6);
+        assert!((hours[0].wirkleistung_min - 100.0).abs() < 1e-6);
+        assert!((hours[0].wirkleistung_max - 300.0).abs() < 1e-6);
+        assert!((hours[0].bezug_first - 1000.0).abs() < 1e-6);
+        assert!((hours[0].bezug_last - 1010.0).abs() < 1e-6);
+        assert_eq!(hours[1].start, at(11, 0).to_rfc3339());
+        assert_eq!(hours[1].samples, 1);
+        assert!((hours[1].einspeisung_last - 8.0).abs() < 1e-6);
+
+        let days =
+            aggregated(query_points(&conn, "haus", Resolution::Day, at(0, 0), at(23, 0)).unwrap());
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].start, at(0, 0).to_rfc3339());
+        assert_eq!(days[0].samples, 3);
+        assert!((days[0].wirkleistung_avg - 200.0 / 3.0).abs() < 1e-6);
+        assert!((days[0].wirkleistung_min + 200.0).abs() < 1e-6);
+        assert!((days[0].wirkleistung_max - 300.0).abs() < 1e-6);
+        assert!((days[0].bezug_first - 1000.0).abs() < 1e-6);
+        assert!((days[0].bezug_last - 1030.0).abs() < 1e-6);
+        assert!((days[0].einspeisung_first - 5.0).abs() < 1e-6);
+        assert!((days[0].einspeisung_last - 8.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn query_range() {
+        let mut conn = database();
+        write_batch(
+            &mut conn,
+            &[
+                row("haus", at(10, 0), 1000.0, 5.0, 100.0),
+                row("haus", at(10, 30), 1010.0, 5.0, -300.0),
+                row("haus", at(11, 15), 1030.0, 8.0, 200.0),
+                row("wp", at(10, 10), 50.0, 0.0, 2000.0),
+            ],
+        )
+        .unwrap();
+
+        // `to` gehört nicht mehr dazu, andere Zähler auch nicht
+        let points =
+            raw(query_points(&conn, "haus", Resolution::Raw, at(10, 0), at(11, 15)).unwrap());
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].time, at(10, 0).to_rfc3339());
+        assert!(!points[0].feed_in);
+        assert_eq!(points[1].time, at(10, 30).to_rfc3339());
+        assert!(points[1].feed_in);
+        assert!((points[1].wirkleistung + 300.0).abs() < 1e-6);
+
+        // `from` mitten in der Stunde: die ganze Stunde zählt
+        let hours = aggregated(
+            query_points(&conn, "haus", Resolution::Hour, at(10, 30), at(11, 0)).unwrap(),
+        );
+        assert_eq!(hours.len(), 1);
+        assert_eq!(hours[0].samples, 2);
+
+        assert!(
+            raw(query_points(&conn, "pv", Resolution::Raw, at(0, 0), at(23, 0)).unwrap())
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn query_limited_to_max_points() {
+        let mut conn = database();
+        let rows: Vec<Row> = (0..MAX_POINTS as i64 + 10)
+            .map(|i| {
+                let time = at(0, 0) + TimeDelta::milliseconds(i * 100);
+                row("haus", time, i as f64, 0.0, 100.0)
+            })
+            .collect();
+        write_batch(&mut conn, &rows).unwrap();
+
+        let points =
+            raw(query_points(&conn, "haus", Resolution::Raw, at(0, 0), at(23, 0)).unwrap());
+        assert_eq!(points.len(), MAX_POINTS);
+        // Die ältesten Punkte, aufsteigend
+        assert!(points[0].bezug.abs() < 1e-6);
+        assert!((points[MAX_POINTS - 1].bezug - (MAX_POINTS - 1) as f64).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,54 @@
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::time::{Duration, Instant};
+
+/// Wie oft die Statistik veröffentlicht wird, auch wenn keine Frames kommen
+pub const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Zähler für empfangene Frames und Fehler eines Zählers. Steigende Fehlerzahlen
+/// deuten auf einen verrutschten Lesekopf oder ein verschmutztes Fenster hin.
+#[derive(Clone, Debug, Default)]
+pub struct FrameStats {
+    /// Vollständig dekodierte Frames (SML-Transport bzw. D0-Telegramm)
+    pub frames_received: u64,
+    /// Frames mit falscher Prüfsumme (CRC)
+    pub checksum_errors: u64,
+    /// Sonstige Fehler im Transportprotokoll (Escape-Folgen, Länge, Abbruch)
+    pub decode_errors: u64,
+    /// Verworfene Bytes außerhalb eines Frames
+    pub discarded_bytes: u64,
+    /// Dekodierte Frames, deren Inhalt nicht gelesen werden konnte
+    pub parse_errors: u64,
+    /// SML-Frames mit einer Nachricht (oder Zeitangabe), deren Typ sml-rs nicht
+    /// kennt, also weder Open-, Close- noch GetListResponse; nicht in `parse_errors`
+    pub unknown_messages: u64,
+    /// Frames mit Zählerstand oder Wirkleistung
+    pub valid_readings: u64,
+    pub last_valid: Option<Instant>,
+}
+
+impl FrameStats {
+    /// Sekunden seit dem letzten gültigen Messwert
+    pub fn seconds_since_valid(&self) -> Option<f64> {
+        self.last_valid.map(|t| t.elapsed().as_secs_f64())
+    }
+}
+
+impl Serialize for FrameStats {
+    /// `last_valid` wird als Alter in Sekunden ausgegeben
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("FrameStats", 8)?;
+        s.serialize_field("frames_received", &self.frames_received)?;
+        s.serialize_field("checksum_errors", &self.checksum_errors)?;
+        s.serialize_field("decode_errors", &self.decode_errors)?;
+        s.serialize_field("discarded_bytes", &self.discarded_bytes)?;
+        s.serialize_field("parse_errors", &self.parse_errors)?;
+        s.serialize_field("unknown_messages", &self.unknown_messages)?;
+        s.serialize_field("valid_readings", &self.valid_readings)?;
+        s.serialize_field(
+            "seconds_since_valid",
+            &self.seconds_since_valid().map(|s| s.round()),
+        )?;
+        s.end()
+    }
+}
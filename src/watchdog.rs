@@ -0,0 +1,91 @@
+use chrono::Local;
+use rumqttc::{AsyncClient, QoS};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::config::MeterConfig;
+use crate::model::{SharedAppState, SseData};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Meldet einen Zähler als nicht verfügbar, wenn länger als `stale_timeout`
+/// kein gültiger Messwert kam (z.B. abgefallener Lesekopf bei offenem Port).
+pub async fn run_watchdog(
+    meter: MeterConfig,
+    app_state: SharedAppState,
+    client: Option<AsyncClient>,
+    token: CancellationToken,
+) {
+    let topic = meter.topics.availability();
+    // Bis zum ersten Messwert gilt der Zähler als nicht verfügbar
+    publish(client.as_ref(), &topic, false);
+
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = token.cancelled() => {
+                publish(client.as_ref(), &topic, false);
+                break;
+            }
+        }
+
+        let event = {
+            let mut state = match app_state.lock() {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let Some(m) = state.meter_mut(&meter.name) else {
+                continue;
+            };
+            let fresh = m
+                .stats
+                .last_valid
+                .is_some_and(|t| t.elapsed() < meter.stale_timeout);
+            if fresh == m.available {
+                continue;
+            }
+            m.available = fresh;
+            let event = SseData {
+                meter: m.name.clone(),
+                time: Local::now().format("%H:%M:%S").to_string(),
+                value: m.wirkleistung,
+                value2: m.zaehlerstand_diff,
+                total_energy: m.zaehlerstand,
+                is_feed_in: m.einspeisung_sts,
+                available: fresh,
+                registers: m.registers.clone(),
+            };
+            let _ = state.tx.send(event);
+            fresh
+        };
+
+        if event {
+            info!("Meter {} is online", meter.name);
+        } else {
+            warn!(
+                "Meter {}: no valid data for {:?}, marking offline",
+                meter.name, meter.stale_timeout
+            );
+        }
+        publish(client.as_ref(), &topic, event);
+    }
+}
+
+/// Ohne MQTT nur SSE und Web-API. Nicht warten: bei getrenntem Broker ist die
+/// Request-Queue von rumqttc schnell voll, die SSE-Ereignisse sollen trotzdem
+/// weiterlaufen. Nach dem Verbinden sendet `Announcer` den aktuellen Stand.
+fn publish(client: Option<&AsyncClient>, topic: &str, online: bool) {
+    let Some(client) = client else {
+        return;
+    };
+    if let Err(e) = client.try_publish(
+        topic,
+        QoS::AtLeastOnce,
+        true,
+        if online { "online" } else { "offline" },
+    ) {
+        warn!("MQTT availability not sent: {:?}", e);
+    }
+}
@@ -1,18 +1,29 @@
-use crate::model::SharedAppState;
+use crate::metrics;
+use crate::model::{MeterState, SharedAppState};
+use crate::obis::Registers;
+#[cfg(feature = "sqlite")]
+use crate::sqlite::{History, HistoryPoints, Resolution};
+use crate::stats::FrameStats;
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
     response::{
         sse::{Event, Sse},
         Html, IntoResponse,
     },
     routing::get,
-    Router,
+    Json, Router,
 };
+#[cfg(feature = "sqlite")]
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use std::path::Path as FsPath;
 use tokio::{fs::File, io::AsyncReadExt};
 use tokio_stream::wrappers::BroadcastStream;
 use tokio_stream::StreamExt;
 use tower_http::services::ServeDir;
+#[cfg(feature = "sqlite")]
+use tracing::error;
 use tracing::info;
 
 // --- 5. Handler für die HTML-Seite (Liest index.html aus dem static-Ordner) ---
@@ -47,8 +58,9 @@ async fn html_handler() -> impl IntoResponse {
 
 pub async fn start_server(
     addr: &str,
-    image_path: &str,
+    image_path: &FsPath,
     shared_state: SharedAppState,
+    #[cfg(feature = "sqlite")] history: Option<History>,
 ) -> anyhow::Result<()> {
     // 1. Service für das Standard-Static-Verzeichnis ('static')
     let static_files_service = ServeDir::new("static");
@@ -61,6 +73,15 @@ pub async fn start_server(
         .route("/", get(html_handler))
         // SSE-Route
         .route("/events", get(sse_handler))
+        // Alle Zähler mit ihren letzten Werten
+        .route("/api/meters", get(meters_handler))
+        // Alle zuletzt empfangenen Register als JSON (erster Zähler bzw. je Zähler)
+        .route("/api/registers", get(registers_handler))
+        .route("/api/meters/{name}/registers", get(meter_registers_handler))
+        // Frame- und Fehlerzähler eines Zählers
+        .route("/api/meters/{name}/stats", get(meter_stats_handler))
+        // Messwerte und Dienstzustand für Prometheus
+        .route("/metrics", get(metrics_handler))
         // Favicon Route (zur Vermeidung des 404-Fehlers)
         .route("/favicon.ico", get(|| async { StatusCode::NO_CONTENT }))
         // Service für statische Dateien (CSS, JS, Bilder etc.)
@@ -70,6 +91,17 @@ pub async fn start_server(
         .with_state(shared_state); // Hinzufügen der State-Weitergabe
                                    //       .with_state(app_state);
 
+    // Messwerte sowie Stunden- und Tageswerte aus SQLite
+    #[cfg(feature = "sqlite")]
+    let app = match history {
+        Some(history) => app.merge(
+            Router::new()
+                .route("/api/meters/{name}/history", get(meter_history_handler))
+                .with_state(history),
+        ),
+        None => app,
+    };
+
     let listener = tokio::net::TcpListener::bind(addr).await?;
     info!("Server läuft auf {}", addr);
     axum::serve(listener, app).await?;
@@ -77,20 +109,116 @@ pub async fn start_server(
     Ok(())
 }
 
+async fn meters_handler(State(state): State<SharedAppState>) -> Json<Vec<MeterState>> {
+    let s = state.lock().unwrap();
+    Json(s.meters.clone())
+}
+
+async fn registers_handler(State(state): State<SharedAppState>) -> Json<Registers> {
+    let s = state.lock().unwrap();
+    Json(
+        s.meters
+            .first()
+            .map(|m| m.registers.clone())
+            .unwrap_or_default(),
+    )
+}
+
+async fn meter_registers_handler(
+    State(state): State<SharedAppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Registers>, StatusCode> {
+    let s = state.lock().unwrap();
+    s.meters
+        .iter()
+        .find(|m| m.name == name)
+        .map(|m| Json(m.registers.clone()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn meter_stats_handler(
+    State(state): State<SharedAppState>,
+    Path(name): Path<String>,
+) -> Result<Json<FrameStats>, StatusCode> {
+    let s = state.lock().unwrap();
+    s.meters
+        .iter()
+        .find(|m| m.name == name)
+        .map(|m| Json(m.stats.clone()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Beginn und Ende als RFC 3339, z.B. `2024-05-01T00:00:00+02:00`
+    from: Option<String>,
+    to: Option<String>,
+    #[serde(default)]
+    resolution: Resolution,
+}
+
+#[cfg(feature = "sqlite")]
+async fn meter_history_handler(
+    State(history): State<History>,
+    Path(name): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryPoints>, StatusCode> {
+    if !history.has_meter(&name) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let parse = |time: Option<String>| {
+        time.map(|t| DateTime::parse_from_rfc3339(&t).map(|t| t.with_timezone(&Local)))
+            .transpose()
+            .map_err(|_| StatusCode::BAD_REQUEST)
+    };
+    let to = parse(query.to)?.unwrap_or_else(Local::now);
+    let from = parse(query.from)?.unwrap_or(to - query.resolution.default_range());
+    tokio::task::spawn_blocking(move || history.query(&name, query.resolution, from, to))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .map_err(|e| {
+            error!("SQLite query failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+async fn metrics_handler(State(state): State<SharedAppState>) -> impl IntoResponse {
+    let body = metrics::render(&state.lock().unwrap());
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        body,
+    )
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// Nur Ereignisse dieses Zählers senden
+    meter: Option<String>,
+}
+
 async fn sse_handler(
     State(state): State<SharedAppState>,
+    Query(query): Query<EventsQuery>,
 ) -> Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>> {
     let rx = {
         let s = state.lock().unwrap();
         s.tx.subscribe()
     };
 
-    let stream = BroadcastStream::new(rx).map(|msg| match msg {
+    let stream = BroadcastStream::new(rx).filter_map(move |msg| match msg {
         Ok(data) => {
+            if query.meter.as_ref().is_some_and(|m| *m != data.meter) {
+                return None;
+            }
             let json = serde_json::to_string(&data).unwrap_or_default();
-            Ok(Event::default().data(json))
+            Some(Ok(Event::default().data(json)))
         }
-        Err(_) => Ok(Event::default()),
+        Err(_) => Some(Ok(Event::default())),
     });
 
     Sse::new(stream).keep_alive(axum::response::sse::KeepAlive::default())
@@ -27,7 +27,8 @@ const translations = {
         chart_axis_consump: "Verbrauch/Intervall (Wh)",
         chart_title: "Echtzeit-Leistungsverlauf",
         status_feed_in: "Einspeisung",
-        status_consumption: "Bezug"
+        status_consumption: "Bezug",
+        meter_offline: "Keine Daten"
     },
     en: {
         app_title: "Power Consumption Monitor",
@@ -56,13 +57,16 @@ const translations = {
         chart_axis_consump: "Consump./Interval (Wh)",
         chart_title: "Real-time Power History",
         status_feed_in: "Grid Feed-in",
-        status_consumption: "Consumption"
+        status_consumption: "Consumption",
+        meter_offline: "No data"
     }
 };
 
 // Global settings
 let currentLang = localStorage.getItem('appLang') || 'de'; // Default to DE
 let liveChart;
+let meters = [];
+let currentMeter = null; // null: only one meter, no filtering
 
 // --- Language Switching Logic ---
 function setLanguage(lang) {
@@ -106,6 +110,11 @@ const eventSource = new EventSource("/events");
 eventSource.onmessage = function (event) {
     try {
         const data = JSON.parse(event.data);
+        if (currentMeter && data.meter !== currentMeter) return;
+
+        // Watchdog: meter stopped sending (e.g. IR head fell off)
+        $('#meter-offline').toggleClass('d-none', data.available !== false);
+        if (data.available === false) return;
 
         // --- Status Page Updates ---
         $('#last-update').text(data.time);
@@ -172,6 +181,14 @@ function updateImage() {
         const timestamp = new Date().getTime();
         let src = $(this).attr('src').split('?')[0];
 
+        // Graphs of the selected meter live in a subdirectory (e.g. /images/pv/strom-tag-de.png)
+        const idx = src.indexOf('/images/');
+        if (idx >= 0) {
+            const file = src.substring(src.lastIndexOf('/') + 1);
+            const meter = meters.find(m => m.name === currentMeter);
+            src = src.substring(0, idx + 8) + (meter ? meter.images : '') + file;
+        }
+
         // Logic to swap language in filename (e.g., strom-tag-de.png <-> strom-tag-en.png)
         // This assumes you have matching images for English on the server.
         if (currentLang === 'en' && src.includes('-de')) {
@@ -187,6 +204,33 @@ function updateImage() {
     window.imageUpdateTimeout = setTimeout(updateImage, 60000);
 }
 
+// --- Meter Selection (only shown with more than one meter) ---
+function loadMeters() {
+    $.getJSON('/api/meters', function (list) {
+        meters = list;
+        if (meters.length < 2) return;
+        const select = $('#meter-select');
+        meters.forEach(m => select.append($('<option>').val(m.name).text(m.name)));
+        select.removeClass('d-none');
+        setMeter(localStorage.getItem('appMeter') || meters[0].name);
+    });
+}
+
+function setMeter(name) {
+    if (!meters.some(m => m.name === name)) name = meters[0].name;
+    currentMeter = name;
+    localStorage.setItem('appMeter', name);
+    $('#meter-select').val(name);
+
+    // Clear the live chart, it only shows one meter at a time
+    if (liveChart) {
+        liveChart.config.data.labels = [];
+        liveChart.config.data.datasets.forEach(ds => ds.data = []);
+        liveChart.update();
+    }
+    updateImage();
+}
+
 // Function to create the Live Chart
 function createLiveChart() {
     const config = {
@@ -301,4 +345,7 @@ $(document).ready(function () {
 
     // 2. Initialize the chart
     liveChart = createLiveChart();
+
+    // 3. Load the configured meters
+    loadMeters();
 });
\ No newline at end of file